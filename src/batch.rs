@@ -0,0 +1,57 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret};
+use super::packet::{AuthenticatedMessage, LocalData, Processed, ProcessError};
+
+use generic_array::ArrayLength;
+use rac::Curve;
+use alloc::vec::Vec;
+
+pub type BatchResult<B, L, N, P> =
+    Result<(<B as Sphinx>::AsymmetricKey, Processed<B, L, N, P>), ProcessError>;
+
+impl<B, L, N, P> AuthenticatedMessage<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    fn process_one<T>(
+        public_key: B::AsymmetricKey,
+        packet: Self,
+        secret_key: &<B::AsymmetricKey as Curve>::Scalar,
+        associated_data: T,
+    ) -> BatchResult<B, L, N, P>
+    where
+        T: AsRef<[u8]>,
+    {
+        let (local, next) = LocalData::next::<B>(secret_key, &public_key)?;
+        let processed = packet.process(associated_data, &local)?;
+        Ok((next, processed))
+    }
+
+    // each packet comes with its public key, the results are in the same order,
+    // one bad packet does not stop the others, the key exchange and the streams
+    // of each packet run on the rayon pool
+    pub fn process_batch<T>(
+        packets: Vec<(B::AsymmetricKey, Self)>,
+        secret_key: &<B::AsymmetricKey as Curve>::Scalar,
+        associated_data: T,
+    ) -> Vec<BatchResult<B, L, N, P>>
+    where
+        B::AsymmetricKey: Send,
+        <B::AsymmetricKey as Curve>::Scalar: Sync,
+        Self: Send,
+        Processed<B, L, N, P>: Send,
+        T: AsRef<[u8]> + Sync,
+    {
+        use rayon::prelude::*;
+
+        packets
+            .into_par_iter()
+            .map(|(public_key, packet)| {
+                Self::process_one(public_key, packet, secret_key, associated_data.as_ref())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,191 @@
+use super::sphinx::{Sphinx, BlindingKeys, SharedSecret, wipe, constant_time_eq};
+use super::packet::{LocalData, ProcessError, exchange};
+
+use generic_array::{GenericArray, typenum::Unsigned};
+use keystream::KeyStream;
+use rac::{LineValid, Curve};
+use core::marker::PhantomData;
+use alloc::vec::Vec;
+
+// encryption of the data the recipient leaves for each hop of a blinded route
+pub trait DataCipher<A>
+where
+    A: Curve,
+{
+    fn seal(shared: &SharedSecret<A>, data: &[u8]) -> Result<Vec<u8>, ProcessError>;
+
+    fn open(shared: &SharedSecret<A>, data: &[u8]) -> Result<Vec<u8>, ProcessError>;
+}
+
+// xor with the `rho` stream followed by the `mu` mac of the ciphertext
+pub struct StreamDataCipher<B>
+where
+    B: Sphinx,
+{
+    phantom_data: PhantomData<B>,
+}
+
+impl<B> DataCipher<B::AsymmetricKey> for StreamDataCipher<B>
+where
+    B: Sphinx,
+{
+    fn seal(shared: &SharedSecret<B::AsymmetricKey>, data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+        let mut data = data.to_vec();
+        B::rho(shared).xor_read(data.as_mut())?;
+        let hmac = B::output(B::chain(B::mu(shared), &data));
+        data.extend_from_slice(hmac.as_ref());
+        Ok(data)
+    }
+
+    fn open(shared: &SharedSecret<B::AsymmetricKey>, data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+        let length = data
+            .len()
+            .checked_sub(B::MacLength::to_usize())
+            .ok_or(ProcessError::PayloadTooShort)?;
+        let (data, hmac) = data.split_at(length);
+        if !constant_time_eq(B::output(B::chain(B::mu(shared), data)).as_slice(), hmac) {
+            return Err(ProcessError::MacMismatch);
+        }
+        let mut data = data.to_vec();
+        B::rho(shared).xor_read(data.as_mut())?;
+        Ok(data)
+    }
+}
+
+fn scalar<A>(bytes: &[u8]) -> Result<A::Scalar, ProcessError>
+where
+    A: Curve,
+{
+    let array =
+        GenericArray::from_exact_iter(bytes.iter().cloned()).ok_or(ProcessError::InvalidScalar)?;
+    A::Scalar::try_clone_array(&array).map_err(|_| ProcessError::InvalidScalar)
+}
+
+pub struct BlindedHop<A>
+where
+    A: Curve,
+{
+    pub node_id: A,
+    pub encrypted_data: Vec<u8>,
+}
+
+// the tail of a route built by the recipient, only the introduction node is known to the sender
+pub struct BlindedPath<A>
+where
+    A: Curve,
+{
+    pub introduction_node: A,
+    pub blinding_point: A,
+    pub hops: Vec<BlindedHop<A>>,
+}
+
+impl<A> BlindedPath<A>
+where
+    A: Curve + Clone,
+    A::Scalar: Clone,
+{
+    pub fn new<H, B, E>(session_key: &A::Scalar, path: H) -> Result<Self, ProcessError>
+    where
+        H: Iterator<Item = (A, Vec<u8>)>,
+        B: BlindingKeys<AsymmetricKey = A>,
+        E: DataCipher<A>,
+    {
+        use rac::Scalar;
+
+        let mut path = path.peekable();
+        let introduction_node = match path.peek() {
+            Some((node_id, _)) => node_id.clone(),
+            None => return Err(ProcessError::EmptyPath),
+        };
+        let blinding_point = A::base().exp_ec(session_key);
+
+        let mut secret = session_key.clone();
+        let mut public = blinding_point.clone();
+        let mut hops = Vec::new();
+        for (node_id, data) in path {
+            let mut shared_secret = B::tau(exchange(&node_id, &secret)?);
+            let tweak = scalar::<A>(B::blinded_node_id(&shared_secret).as_ref())?;
+            hops.push(BlindedHop {
+                node_id: node_id.exp_ec(&tweak),
+                encrypted_data: E::seal(&shared_secret, data.as_ref())?,
+            });
+
+            let blinding = scalar::<A>(B::blinding(&public, &shared_secret).as_ref())?;
+            wipe(shared_secret.as_mut());
+            secret = secret
+                .mul_ff(&blinding)
+                .map_err(|_| ProcessError::InvalidScalar)?;
+            public = A::base().exp_ec(&secret);
+        }
+
+        Ok(BlindedPath {
+            introduction_node: introduction_node,
+            blinding_point: blinding_point,
+            hops: hops,
+        })
+    }
+}
+
+// what a hop of a blinded route derives from its node secret and the blinding point,
+// the packet is processed with the tweaked `secret_key`, kept as bytes so it is wiped
+// on drop, the scalar it is decoded into is wiped only if its own type does so
+pub struct BlindedRelay<A>
+where
+    A: Curve,
+{
+    secret_key: GenericArray<u8, <A::Scalar as LineValid>::Length>,
+    shared_secret: SharedSecret<A>,
+    next_blinding_point: A,
+}
+
+impl<A> Drop for BlindedRelay<A>
+where
+    A: Curve,
+{
+    fn drop(&mut self) {
+        wipe(self.secret_key.as_mut());
+        wipe(self.shared_secret.as_mut());
+    }
+}
+
+impl<A> BlindedRelay<A>
+where
+    A: Curve,
+{
+    pub fn new<B>(secret_key: &A::Scalar, blinding_point: &A) -> Result<Self, ProcessError>
+    where
+        B: BlindingKeys<AsymmetricKey = A>,
+    {
+        use rac::Scalar;
+
+        let (local, next_blinding_point) = LocalData::next::<B>(secret_key, blinding_point)?;
+        let tweak = scalar::<A>(B::blinded_node_id(&local.shared_secret).as_ref())?;
+        let secret_key = secret_key
+            .mul_ff(&tweak)
+            .map_err(|_| ProcessError::InvalidScalar)?;
+        Ok(BlindedRelay {
+            secret_key: secret_key.clone_line(),
+            shared_secret: local.shared_secret.clone(),
+            next_blinding_point: next_blinding_point,
+        })
+    }
+
+    pub fn secret_key(&self) -> Result<A::Scalar, ProcessError> {
+        A::Scalar::try_clone_array(&self.secret_key).map_err(|_| ProcessError::InvalidScalar)
+    }
+
+    pub fn shared_secret(&self) -> &SharedSecret<A> {
+        &self.shared_secret
+    }
+
+    pub fn next_blinding_point(&self) -> &A {
+        &self.next_blinding_point
+    }
+
+    pub fn decrypt<E>(&self, encrypted_data: &[u8]) -> Result<Vec<u8>, ProcessError>
+    where
+        E: DataCipher<A>,
+    {
+        E::open(&self.shared_secret, encrypted_data)
+    }
+}
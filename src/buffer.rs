@@ -0,0 +1,105 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, PayloadCipher, constant_time_eq};
+use super::packet::{LocalData, ProcessError, strip_tag, is_exit};
+
+use generic_array::{GenericArray, ArrayLength, typenum::Unsigned};
+use keystream::KeyStream;
+use core::marker::PhantomData;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum BufferProcessed<L>
+where
+    L: ArrayLength<u8>,
+{
+    // the buffer holds the packet for the next hop
+    Forward { data: GenericArray<u8, L> },
+    // the buffer holds the decrypted message after the header
+    Exit { data: GenericArray<u8, L> },
+}
+
+// processes a packet right in the receive buffer, the layout is the one
+// the serialized `AuthenticatedMessage` has, routing info || hmac || message
+pub struct PacketBuffer<B, L, N>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+{
+    phantom_data: PhantomData<(B, L, N)>,
+}
+
+impl<B, L, N> PacketBuffer<B, L, N>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+{
+    fn routing_info_size() -> usize {
+        N::to_usize() * PayloadHmac::<L, B::MacLength>::size()
+    }
+
+    pub fn header_size() -> usize {
+        Self::routing_info_size() + B::MacLength::to_usize()
+    }
+
+    // checks whether the packet is meant for the key, the buffer is untouched
+    pub fn verify<T>(buffer: &[u8], associated_data: T, local: &LocalData<B::AsymmetricKey>) -> bool
+    where
+        T: AsRef<[u8]>,
+    {
+        if buffer.len() < Self::header_size() {
+            return false;
+        }
+        let (routing_info, rest) = buffer.split_at(Self::routing_info_size());
+        let hmac = &rest[..B::MacLength::to_usize()];
+        let mu = B::chain(B::mu(&local.shared_secret), routing_info);
+        constant_time_eq(
+            B::output(B::chain(mu, associated_data.as_ref())).as_slice(),
+            hmac,
+        )
+    }
+
+    // the same as `AuthenticatedMessage::process`, but nothing is allocated or cloned,
+    // the routing info is shifted with a memmove and the message is decrypted in place,
+    // on error the buffer may be left partially processed
+    pub fn process<T>(
+        buffer: &mut [u8],
+        associated_data: T,
+        local: &LocalData<B::AsymmetricKey>,
+    ) -> Result<BufferProcessed<L>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+    {
+        if buffer.len() < Self::header_size() {
+            return Err(ProcessError::MalformedPacket);
+        }
+        if !Self::verify(buffer, associated_data, local) {
+            return Err(ProcessError::MacMismatch);
+        }
+
+        let routing_info_size = Self::routing_info_size();
+        let item_size = PayloadHmac::<L, B::MacLength>::size();
+        let (header, message) = buffer.split_at_mut(Self::header_size());
+        let (routing_info, hmac) = header.split_at_mut(routing_info_size);
+
+        let mut stream = B::rho(&local.shared_secret);
+        let mut data = GenericArray::<u8, L>::clone_from_slice(&routing_info[..L::to_usize()]);
+        hmac.copy_from_slice(&routing_info[L::to_usize()..item_size]);
+        stream.xor_read(data.as_mut())?;
+        stream.xor_read(hmac)?;
+
+        routing_info.copy_within(item_size.., 0);
+        routing_info[(routing_info_size - item_size)..]
+            .iter_mut()
+            .for_each(|x| *x = 0);
+        stream.xor_read(routing_info)?;
+
+        B::PayloadCipher::decrypt(&local.shared_secret, message)?;
+        if is_exit::<B>(&local.shared_secret, hmac) {
+            strip_tag::<B>(message)?;
+            Ok(BufferProcessed::Exit { data: data })
+        } else {
+            Ok(BufferProcessed::Forward { data: data })
+        }
+    }
+}
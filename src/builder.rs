@@ -0,0 +1,252 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret, PayloadCipher, wipe};
+use super::packet::ProcessError;
+use super::onion::OnionPacket;
+
+use generic_array::{GenericArray, ArrayLength};
+use rac::{LineValid, Curve};
+use rand_core::{RngCore, CryptoRng};
+use core::marker::PhantomData;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BuildError {
+    EmptyPath,
+    PathTooLong {
+        hops: usize,
+        max: usize,
+    },
+    PayloadTooLong {
+        hop: usize,
+        length: usize,
+        max: usize,
+    },
+    MissingMessage,
+    MessageTooShort {
+        length: usize,
+        min: usize,
+    },
+    MessageTooLong {
+        max: usize,
+    },
+    Process(ProcessError),
+}
+
+impl From<ProcessError> for BuildError {
+    fn from(e: ProcessError) -> Self {
+        BuildError::Process(e)
+    }
+}
+
+// collects the route hop by hop, nothing is computed until `build`,
+// which checks the route and the message before any key is drawn,
+// the packet is always the padded one, see `AuthenticatedMessage::with_padding`
+pub struct PacketBuilder<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]>,
+{
+    hops: Vec<(B::AsymmetricKey, Vec<u8>)>,
+    associated_data: Vec<u8>,
+    message: Option<P>,
+    phantom_data: PhantomData<(L, N)>,
+}
+
+impl<B, L, N, P> PacketBuilder<B, L, N, P>
+where
+    B: Sphinx,
+    B::AsymmetricKey: Clone,
+    <B::AsymmetricKey as Curve>::Scalar: Clone,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    // how many times `build` draws a session key before it gives up on the rng
+    const SESSION_KEY_ATTEMPTS: usize = 16;
+
+    pub fn new() -> Self {
+        PacketBuilder {
+            hops: Vec::new(),
+            associated_data: Vec::new(),
+            message: None,
+            phantom_data: PhantomData,
+        }
+    }
+
+    // the payload may be shorter than `L`, the rest of the slot is zero
+    pub fn hop<T>(mut self, public_key: B::AsymmetricKey, payload: T) -> Self
+    where
+        T: AsRef<[u8]>,
+    {
+        self.hops.push((public_key, payload.as_ref().to_vec()));
+        self
+    }
+
+    pub fn associated_data<T>(mut self, associated_data: T) -> Self
+    where
+        T: AsRef<[u8]>,
+    {
+        self.associated_data = associated_data.as_ref().to_vec();
+        self
+    }
+
+    pub fn message(mut self, message: P) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    pub fn build<R>(mut self, rng: &mut R) -> Result<OnionPacket<B, L, N, P>, BuildError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        self.validate()?;
+
+        let mut bytes = GenericArray::default();
+        for _ in 0..Self::SESSION_KEY_ATTEMPTS {
+            rng.fill_bytes(bytes.as_mut());
+            if let Ok(session_key) = <B::AsymmetricKey as Curve>::Scalar::try_clone_array(&bytes) {
+                wipe(bytes.as_mut());
+                return self.build_unchecked(&session_key);
+            }
+        }
+        wipe(bytes.as_mut());
+        Err(BuildError::Process(ProcessError::InvalidScalar))
+    }
+
+    // the same as `build`, but the caller picks the session key,
+    // it must be fresh for every packet, reusing it links the packets
+    pub fn build_with_session_key(
+        mut self,
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+    ) -> Result<OnionPacket<B, L, N, P>, BuildError> {
+        self.validate()?;
+        self.build_unchecked(session_key)
+    }
+
+    fn validate(&mut self) -> Result<(), BuildError> {
+        if self.hops.is_empty() {
+            return Err(BuildError::EmptyPath);
+        }
+        if self.hops.len() > N::to_usize() {
+            return Err(BuildError::PathTooLong {
+                hops: self.hops.len(),
+                max: N::to_usize(),
+            });
+        }
+        let too_long = self.hops.iter().position(|hop| hop.1.len() > L::to_usize());
+        if let Some(hop) = too_long {
+            return Err(BuildError::PayloadTooLong {
+                hop: hop,
+                length: self.hops[hop].1.len(),
+                max: L::to_usize(),
+            });
+        }
+
+        let message = match self.message {
+            Some(ref mut message) => message.as_mut(),
+            None => return Err(BuildError::MissingMessage),
+        };
+        let length = message.len();
+        let min = B::PayloadCipher::tag_length();
+        if length < min {
+            return Err(BuildError::MessageTooShort {
+                length: length,
+                min: min,
+            });
+        }
+        // the tag is prepended in place, the last `min` bytes are reserved for it
+        if message[(length - min)..].iter().any(|&b| b != 0) {
+            return Err(BuildError::MessageTooLong { max: length - min });
+        }
+        Ok(())
+    }
+
+    fn build_unchecked(
+        self,
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+    ) -> Result<OnionPacket<B, L, N, P>, BuildError> {
+        let PacketBuilder {
+            hops: hops,
+            associated_data: associated_data,
+            message: message,
+            ..
+        } = self;
+        let message = message.ok_or(BuildError::MissingMessage)?;
+        let (path, payloads): (Vec<_>, Vec<_>) = hops
+            .into_iter()
+            .map(|(public_key, payload)| {
+                let mut data = GenericArray::<u8, L>::default();
+                data[..payload.len()].copy_from_slice(payload.as_ref());
+                (public_key, data)
+            })
+            .unzip();
+        OnionPacket::with_padding(
+            session_key,
+            path.into_iter(),
+            associated_data,
+            payloads.into_iter(),
+            message,
+        )
+        .map_err(BuildError::Process)
+    }
+}
+
+impl<B, L, N, P> Default for PacketBuilder<B, L, N, P>
+where
+    B: Sphinx,
+    B::AsymmetricKey: Clone,
+    <B::AsymmetricKey as Curve>::Scalar: Clone,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod implementations {
+    use super::BuildError;
+    use core::fmt;
+
+    impl fmt::Display for BuildError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                BuildError::EmptyPath => write!(f, "path is empty"),
+                BuildError::PathTooLong {
+                    hops: hops,
+                    max: max,
+                } => write!(f, "path of {} hops, at most {} fit", hops, max),
+                BuildError::PayloadTooLong {
+                    hop: hop,
+                    length: length,
+                    max: max,
+                } => write!(
+                    f,
+                    "payload of hop {} is {} bytes, at most {} fit",
+                    hop, length, max
+                ),
+                BuildError::MissingMessage => write!(f, "message is not set"),
+                BuildError::MessageTooShort {
+                    length: length,
+                    min: min,
+                } => write!(
+                    f,
+                    "message is {} bytes, at least {} are needed",
+                    length, min
+                ),
+                BuildError::MessageTooLong { max: max } => write!(
+                    f,
+                    "message overlaps the bytes reserved for the tag, at most {} fit",
+                    max
+                ),
+                BuildError::Process(ref e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for BuildError {}
+}
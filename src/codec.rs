@@ -0,0 +1,216 @@
+use super::path::{Path, PayloadHmac};
+use super::sphinx::Sphinx;
+use super::packet::AuthenticatedMessage;
+
+use generic_array::{GenericArray, ArrayLength, typenum::Unsigned};
+use rac::{LineValid, Curve};
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CodecError {
+    // the buffer or the input has not exactly the size of the encoding
+    Length { expected: usize, actual: usize },
+    UnknownVersion(u8),
+    InvalidPoint,
+}
+
+pub(crate) fn check_length(expected: usize, actual: usize) -> Result<(), CodecError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(CodecError::Length {
+            expected: expected,
+            actual: actual,
+        })
+    }
+}
+
+// the ephemeral public key is its compressed form, as `LineValid` gives it
+pub fn point_size<A>() -> usize
+where
+    A: Curve,
+{
+    <A::CompressedCurve as LineValid>::Length::to_usize()
+}
+
+pub fn encode_point_into<A>(point: &A, buffer: &mut [u8]) -> Result<(), CodecError>
+where
+    A: Curve,
+{
+    check_length(point_size::<A>(), buffer.len())?;
+    buffer.copy_from_slice(point.compress().clone_line().as_ref());
+    Ok(())
+}
+
+#[cfg(feature = "alloc")]
+pub fn point_to_bytes<A>(point: &A) -> Vec<u8>
+where
+    A: Curve,
+{
+    point.compress().clone_line().to_vec()
+}
+
+pub fn point_from_bytes<A>(bytes: &[u8]) -> Result<A, CodecError>
+where
+    A: Curve,
+{
+    check_length(point_size::<A>(), bytes.len())?;
+    let compressed = LineValid::try_clone_array(GenericArray::from_slice(bytes))
+        .map_err(|_| CodecError::InvalidPoint)?;
+    A::decompress(&compressed).map_err(|_| CodecError::InvalidPoint)
+}
+
+// each item is data || hmac, the items go in the order of the path,
+// there is no version, the path is always a part of a bigger encoding
+impl<L, M, N> Path<L, M, N>
+where
+    L: ArrayLength<u8>,
+    M: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, M>>,
+{
+    pub fn encoded_size() -> usize {
+        N::to_usize() * PayloadHmac::<L, M>::size()
+    }
+
+    pub fn encode_into(&self, buffer: &mut [u8]) -> Result<(), CodecError> {
+        check_length(Self::encoded_size(), buffer.len())?;
+        let mut rest = buffer;
+        for item in self.as_ref() {
+            let (data, tail) = rest.split_at_mut(L::to_usize());
+            let (hmac, tail) = tail.split_at_mut(M::to_usize());
+            data.copy_from_slice(item.data.as_ref());
+            hmac.copy_from_slice(item.hmac.as_ref());
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; Self::encoded_size()];
+        self.encode_into(bytes.as_mut())
+            .expect("the buffer has the right size");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        check_length(Self::encoded_size(), bytes.len())?;
+        let mut path = Path::new();
+        let mut rest = bytes;
+        for item in path.as_mut() {
+            let (data, tail) = rest.split_at(L::to_usize());
+            let (hmac, tail) = tail.split_at(M::to_usize());
+            item.data = GenericArray::clone_from_slice(data);
+            item.hmac = GenericArray::clone_from_slice(hmac);
+            rest = tail;
+        }
+        Ok(path)
+    }
+}
+
+// version || routing info || hmac || message, the same order the serde tuple has,
+// the size of the message is fixed by its type, so `from_bytes` takes it from `P::default`
+impl<B, L, N, P> AuthenticatedMessage<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]> + AsRef<[u8]>,
+{
+    pub const VERSION: u8 = 0;
+
+    pub fn header_size() -> usize {
+        1 + Self::body_header_size()
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        Self::header_size() + self.message.as_ref().len()
+    }
+
+    pub fn encode_into(&self, buffer: &mut [u8]) -> Result<(), CodecError> {
+        check_length(self.encoded_size(), buffer.len())?;
+        buffer[0] = Self::VERSION;
+        self.encode_body_into(&mut buffer[1..])
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.encoded_size()];
+        self.encode_into(bytes.as_mut())
+            .expect("the buffer has the right size");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError>
+    where
+        P: Default,
+    {
+        let message_size = P::default().as_ref().len();
+        check_length(Self::header_size() + message_size, bytes.len())?;
+        if bytes[0] != Self::VERSION {
+            return Err(CodecError::UnknownVersion(bytes[0]));
+        }
+        Self::from_body_bytes(&bytes[1..])
+    }
+
+    // the encoding without the version, the onion packet puts its own header in front
+    pub(crate) fn body_header_size() -> usize {
+        Path::<L, B::MacLength, N>::encoded_size() + B::MacLength::to_usize()
+    }
+
+    pub(crate) fn encode_body_into(&self, buffer: &mut [u8]) -> Result<(), CodecError> {
+        check_length(
+            Self::body_header_size() + self.message.as_ref().len(),
+            buffer.len(),
+        )?;
+        let (routing_info, rest) = buffer.split_at_mut(Path::<L, B::MacLength, N>::encoded_size());
+        let (hmac, message) = rest.split_at_mut(B::MacLength::to_usize());
+        self.routing_info.encode_into(routing_info)?;
+        hmac.copy_from_slice(self.hmac.as_ref());
+        message.copy_from_slice(self.message.as_ref());
+        Ok(())
+    }
+
+    pub(crate) fn from_body_bytes(bytes: &[u8]) -> Result<Self, CodecError>
+    where
+        P: Default,
+    {
+        let mut message = P::default();
+        check_length(
+            Self::body_header_size() + message.as_ref().len(),
+            bytes.len(),
+        )?;
+        let (routing_info, rest) = bytes.split_at(Path::<L, B::MacLength, N>::encoded_size());
+        let (hmac, rest) = rest.split_at(B::MacLength::to_usize());
+        message.as_mut().copy_from_slice(rest);
+        Ok(AuthenticatedMessage {
+            routing_info: Path::from_bytes(routing_info)?,
+            hmac: GenericArray::clone_from_slice(hmac),
+            message: message,
+        })
+    }
+}
+
+mod implementations {
+    use super::CodecError;
+    use core::fmt;
+
+    impl fmt::Display for CodecError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                CodecError::Length {
+                    expected: expected,
+                    actual: actual,
+                } => write!(f, "expected {} bytes, got {}", expected, actual),
+                CodecError::UnknownVersion(version) => {
+                    write!(f, "unknown packet version {}", version)
+                },
+                CodecError::InvalidPoint => write!(f, "invalid point"),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for CodecError {}
+}
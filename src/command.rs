@@ -0,0 +1,159 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret};
+use super::packet::{AuthenticatedMessage, GlobalData, Processed, ProcessError};
+
+use generic_array::{GenericArray, ArrayLength};
+use alloc::vec::Vec;
+
+// what a hop should do with the packet, each command is tag || length || value,
+// a zero tag is padding and ends the list, the tags from 0x80 up are extensions,
+// a node skips an extension it does not know, but rejects any other unknown tag
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RoutingCommand {
+    NextHop(Vec<u8>),
+    // in milliseconds
+    Delay(u32),
+    Recipient(Vec<u8>),
+    SurbReply([u8; 16]),
+    Extension(u8, Vec<u8>),
+}
+
+impl RoutingCommand {
+    const PADDING: u8 = 0;
+    const NEXT_HOP: u8 = 1;
+    const DELAY: u8 = 2;
+    const RECIPIENT: u8 = 3;
+    const SURB_REPLY: u8 = 4;
+    const EXTENSION: u8 = 0x80;
+
+    fn tag(&self) -> u8 {
+        match *self {
+            RoutingCommand::NextHop(_) => Self::NEXT_HOP,
+            RoutingCommand::Delay(_) => Self::DELAY,
+            RoutingCommand::Recipient(_) => Self::RECIPIENT,
+            RoutingCommand::SurbReply(_) => Self::SURB_REPLY,
+            RoutingCommand::Extension(tag, _) => tag,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        match *self {
+            RoutingCommand::NextHop(ref address) => address.clone(),
+            RoutingCommand::Delay(delay) => delay.to_be_bytes().to_vec(),
+            RoutingCommand::Recipient(ref recipient) => recipient.clone(),
+            RoutingCommand::SurbReply(ref id) => id.to_vec(),
+            RoutingCommand::Extension(_, ref data) => data.clone(),
+        }
+    }
+
+    // the rest of the slot is zero
+    pub fn encode<L>(commands: &[RoutingCommand]) -> Result<GenericArray<u8, L>, ProcessError>
+    where
+        L: ArrayLength<u8>,
+    {
+        let mut data = GenericArray::<u8, L>::default();
+        let mut position = 0;
+        for command in commands {
+            let tag = command.tag();
+            if let RoutingCommand::Extension(..) = *command {
+                if tag < Self::EXTENSION {
+                    return Err(ProcessError::UnknownCommand(tag));
+                }
+            }
+            let value = command.value();
+            if value.len() > 0xff {
+                return Err(ProcessError::CommandsTooLong);
+            }
+            let end = position + 2 + value.len();
+            if end > data.len() {
+                return Err(ProcessError::CommandsTooLong);
+            }
+            data[position] = tag;
+            data[position + 1] = value.len() as u8;
+            data[(position + 2)..end].copy_from_slice(value.as_ref());
+            position = end;
+        }
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Vec<RoutingCommand>, ProcessError> {
+        let mut commands = Vec::new();
+        let mut rest = data;
+        while let Some((&tag, tail)) = rest.split_first() {
+            if tag == Self::PADDING {
+                break;
+            }
+            let (&length, tail) = tail.split_first().ok_or(ProcessError::MalformedHopData)?;
+            if (length as usize) > tail.len() {
+                return Err(ProcessError::MalformedHopData);
+            }
+            let (value, tail) = tail.split_at(length as usize);
+            rest = tail;
+
+            let command = match tag {
+                Self::NEXT_HOP => RoutingCommand::NextHop(value.to_vec()),
+                Self::DELAY => {
+                    if value.len() != 4 {
+                        return Err(ProcessError::MalformedHopData);
+                    }
+                    let mut array = [0; 4];
+                    array.copy_from_slice(value);
+                    RoutingCommand::Delay(u32::from_be_bytes(array))
+                },
+                Self::RECIPIENT => RoutingCommand::Recipient(value.to_vec()),
+                Self::SURB_REPLY => {
+                    if value.len() != 16 {
+                        return Err(ProcessError::MalformedHopData);
+                    }
+                    let mut id = [0; 16];
+                    id.copy_from_slice(value);
+                    RoutingCommand::SurbReply(id)
+                },
+                t if t >= Self::EXTENSION => RoutingCommand::Extension(t, value.to_vec()),
+                t => return Err(ProcessError::UnknownCommand(t)),
+            };
+            commands.push(command);
+        }
+        Ok(commands)
+    }
+}
+
+impl<B, L, N, P> AuthenticatedMessage<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    // the same as `new`, but each hop gets a list of commands rather than raw bytes
+    pub fn with_commands<T, H>(
+        data: GlobalData<B::AsymmetricKey, N>,
+        associated_data: T,
+        commands: H,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        T: AsRef<[u8]>,
+        H: Iterator<Item = Vec<RoutingCommand>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let payloads = commands
+            .map(|commands| RoutingCommand::encode::<L>(commands.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(data, associated_data, payloads.into_iter(), message)
+    }
+}
+
+impl<B, L, N, P> Processed<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]>,
+{
+    pub fn commands(&self) -> Result<Vec<RoutingCommand>, ProcessError> {
+        match *self {
+            Processed::Forward { ref data, .. } => RoutingCommand::decode(data.as_ref()),
+            Processed::Exit { ref data, .. } => RoutingCommand::decode(data.as_ref()),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use super::sphinx::{Sphinx, ErrorKeys, SharedSecret, constant_time_eq};
+use super::packet::{GlobalData, LocalData, ProcessError};
+
+use generic_array::{GenericArray, ArrayLength, typenum::Unsigned};
+use keystream::KeyStream;
+use core::marker::PhantomData;
+use alloc::{vec, vec::Vec};
+
+// hmac || length of the failure || failure || length of the pad || pad,
+// the lengths are 2 bytes big endian, each hop on the way back obfuscates it once more
+pub struct ErrorPacket<B>
+where
+    B: Sphinx,
+{
+    data: Vec<u8>,
+    phantom_data: PhantomData<B>,
+}
+
+impl<B> ErrorPacket<B>
+where
+    B: ErrorKeys,
+{
+    // short failures are padded, so the length does not reveal which one it is
+    pub const FAILURE_LENGTH: usize = 256;
+
+    fn hmac(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        data: &[u8],
+    ) -> GenericArray<u8, B::MacLength> {
+        B::output(B::chain(B::um(shared_secret), data))
+    }
+
+    fn obfuscate(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        data: &mut [u8],
+    ) -> Result<(), ProcessError> {
+        B::ammag(shared_secret)
+            .xor_read(data)
+            .map_err(ProcessError::Stream)
+    }
+
+    // the failing hop creates the packet
+    pub fn new(local: &LocalData<B::AsymmetricKey>, failure: &[u8]) -> Result<Self, ProcessError> {
+        if failure.len() > u16::MAX as usize {
+            return Err(ProcessError::MalformedPacket);
+        }
+        let pad_length = Self::FAILURE_LENGTH.saturating_sub(failure.len());
+
+        let mac_length = B::MacLength::to_usize();
+        let mut data = vec![0; mac_length];
+        data.extend_from_slice(&(failure.len() as u16).to_be_bytes());
+        data.extend_from_slice(failure);
+        data.extend_from_slice(&(pad_length as u16).to_be_bytes());
+        data.resize(data.len() + pad_length, 0);
+        let hmac = Self::hmac(&local.shared_secret, &data[mac_length..]);
+        data[..mac_length].copy_from_slice(hmac.as_ref());
+
+        Self::obfuscate(&local.shared_secret, data.as_mut())?;
+        Ok(ErrorPacket {
+            data: data,
+            phantom_data: PhantomData,
+        })
+    }
+
+    // every other hop on the way back
+    pub fn forward(self, local: &LocalData<B::AsymmetricKey>) -> Result<Self, ProcessError> {
+        let mut data = self.data;
+        Self::obfuscate(&local.shared_secret, data.as_mut())?;
+        Ok(ErrorPacket {
+            data: data,
+            phantom_data: PhantomData,
+        })
+    }
+
+    // `None` if the layer is not the one of the failing hop
+    fn unwrap(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        packet: &[u8],
+    ) -> Result<Option<Vec<u8>>, ProcessError> {
+        let (hmac, rest) = packet.split_at(B::MacLength::to_usize());
+        if !constant_time_eq(Self::hmac(shared_secret, rest).as_slice(), hmac) {
+            return Ok(None);
+        }
+
+        let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        if rest.len() < 4 + length {
+            return Err(ProcessError::MalformedPacket);
+        }
+        let pad_length = u16::from_be_bytes([rest[2 + length], rest[3 + length]]) as usize;
+        if rest.len() != 4 + length + pad_length {
+            return Err(ProcessError::MalformedPacket);
+        }
+        Ok(Some(rest[2..(2 + length)].to_vec()))
+    }
+
+    // the origin peels the layers until the hmac matches,
+    // returns the index of the failing hop and the failure
+    pub fn open<N>(
+        self,
+        data: &GlobalData<B::AsymmetricKey, N>,
+    ) -> Result<(usize, Vec<u8>), ProcessError>
+    where
+        N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    {
+        let mut packet = self.data;
+        for (index, shared_secret) in data.shared_secrets.iter().enumerate() {
+            Self::obfuscate(shared_secret, packet.as_mut())?;
+            if let Some(failure) = Self::unwrap(shared_secret, packet.as_ref())? {
+                return Ok((index, failure));
+            }
+        }
+        Err(ProcessError::MacMismatch)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProcessError> {
+        if bytes.len() < B::MacLength::to_usize() + 4 {
+            Err(ProcessError::MalformedPacket)
+        } else {
+            Ok(ErrorPacket {
+                data: bytes.to_vec(),
+                phantom_data: PhantomData,
+            })
+        }
+    }
+}
+
+// the failure message followed by the attribution data: hold times of the hops
+// and a triangle of truncated hmacs, every hop computes one hmac for each position
+// it might take in the route, because it does not know its position
+pub struct AttributableErrorPacket<B>
+where
+    B: Sphinx,
+{
+    packet: ErrorPacket<B>,
+    attribution: Vec<u8>,
+}
+
+// what the origin learns from an attributable failure
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Attribution {
+    // the hold times reported by the hops, starting from the first one
+    pub hold_times: Vec<u32>,
+    // the first hop whose hmac does not match, either it or its upstream link misbehaved
+    pub misbehaving: Option<usize>,
+    // the index of the failing hop and the failure, if it is authentic
+    pub failure: Option<(usize, Vec<u8>)>,
+}
+
+impl<B> AttributableErrorPacket<B>
+where
+    B: ErrorKeys,
+{
+    pub const MAX_HOPS: usize = 20;
+    pub const HOLD_TIME_LENGTH: usize = 4;
+    pub const HMAC_LENGTH: usize = 4;
+    pub const ATTRIBUTION_LENGTH: usize =
+        Self::MAX_HOPS * Self::HOLD_TIME_LENGTH + Self::TRIANGLE * Self::HMAC_LENGTH;
+
+    const TRIANGLE: usize = Self::MAX_HOPS * (Self::MAX_HOPS + 1) / 2;
+
+    // the segment `s` belongs to the hop `s` steps downstream,
+    // it keeps the hmacs for positions from `s` to the end
+    fn entry(segment: usize, position: usize) -> usize {
+        let offset = segment * Self::MAX_HOPS - segment * segment.saturating_sub(1) / 2;
+        Self::MAX_HOPS * Self::HOLD_TIME_LENGTH + (offset + position - segment) * Self::HMAC_LENGTH
+    }
+
+    fn hmac(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        message: &[u8],
+        attribution: &[u8],
+        position: usize,
+    ) -> Vec<u8> {
+        let remaining = Self::MAX_HOPS - position;
+        let collector = B::chain(B::um(shared_secret), message);
+        let collector = B::chain(
+            collector,
+            &attribution[..(remaining * Self::HOLD_TIME_LENGTH)],
+        );
+        let collector = (1..remaining).fold(collector, |collector, step| {
+            let start = Self::entry(step, position + step);
+            B::chain(collector, &attribution[start..(start + Self::HMAC_LENGTH)])
+        });
+        B::output(collector)[..Self::HMAC_LENGTH].to_vec()
+    }
+
+    // every segment moves one step downstream, the entries which can not be
+    // checked any more fall out
+    fn shift(attribution: &[u8]) -> Vec<u8> {
+        let mut shifted = vec![0; Self::ATTRIBUTION_LENGTH];
+        let hold_times = Self::MAX_HOPS * Self::HOLD_TIME_LENGTH;
+        shifted[Self::HOLD_TIME_LENGTH..hold_times]
+            .copy_from_slice(&attribution[..(hold_times - Self::HOLD_TIME_LENGTH)]);
+        for segment in 1..Self::MAX_HOPS {
+            for position in segment..Self::MAX_HOPS {
+                let from = Self::entry(segment - 1, position);
+                let to = Self::entry(segment, position);
+                shifted[to..(to + Self::HMAC_LENGTH)]
+                    .copy_from_slice(&attribution[from..(from + Self::HMAC_LENGTH)]);
+            }
+        }
+        shifted
+    }
+
+    // the inverse of the above, what fell out is left zero
+    fn unshift(attribution: &[u8]) -> Vec<u8> {
+        let mut shifted = vec![0; Self::ATTRIBUTION_LENGTH];
+        let hold_times = Self::MAX_HOPS * Self::HOLD_TIME_LENGTH;
+        shifted[..(hold_times - Self::HOLD_TIME_LENGTH)]
+            .copy_from_slice(&attribution[Self::HOLD_TIME_LENGTH..hold_times]);
+        for segment in 1..Self::MAX_HOPS {
+            for position in segment..Self::MAX_HOPS {
+                let from = Self::entry(segment, position);
+                let to = Self::entry(segment - 1, position);
+                shifted[to..(to + Self::HMAC_LENGTH)]
+                    .copy_from_slice(&attribution[from..(from + Self::HMAC_LENGTH)]);
+            }
+        }
+        shifted
+    }
+
+    fn obfuscate(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        attribution: &mut [u8],
+    ) -> Result<(), ProcessError> {
+        B::ammag_ext(shared_secret)
+            .xor_read(attribution)
+            .map_err(ProcessError::Stream)
+    }
+
+    fn attribute(
+        packet: ErrorPacket<B>,
+        attribution: &[u8],
+        local: &LocalData<B::AsymmetricKey>,
+        hold_time: u32,
+    ) -> Result<Self, ProcessError> {
+        let mut attribution = Self::shift(attribution);
+        attribution[..Self::HOLD_TIME_LENGTH].copy_from_slice(&hold_time.to_be_bytes());
+        for position in 0..Self::MAX_HOPS {
+            let hmac = Self::hmac(
+                &local.shared_secret,
+                packet.data.as_ref(),
+                attribution.as_ref(),
+                position,
+            );
+            let start = Self::entry(0, position);
+            attribution[start..(start + Self::HMAC_LENGTH)].copy_from_slice(hmac.as_ref());
+        }
+        Self::obfuscate(&local.shared_secret, attribution.as_mut())?;
+        Ok(AttributableErrorPacket {
+            packet: packet,
+            attribution: attribution,
+        })
+    }
+
+    // the failing hop creates the packet
+    pub fn new(
+        local: &LocalData<B::AsymmetricKey>,
+        failure: &[u8],
+        hold_time: u32,
+    ) -> Result<Self, ProcessError> {
+        let packet = ErrorPacket::new(local, failure)?;
+        Self::attribute(packet, &vec![0; Self::ATTRIBUTION_LENGTH], local, hold_time)
+    }
+
+    // every other hop on the way back
+    pub fn forward(
+        self,
+        local: &LocalData<B::AsymmetricKey>,
+        hold_time: u32,
+    ) -> Result<Self, ProcessError> {
+        let packet = self.packet.forward(local)?;
+        Self::attribute(packet, self.attribution.as_ref(), local, hold_time)
+    }
+
+    // the origin checks the hops one by one, stops at the first one
+    // which misbehaved or at the one which failed
+    pub fn open<N>(
+        self,
+        data: &GlobalData<B::AsymmetricKey, N>,
+    ) -> Result<Attribution, ProcessError>
+    where
+        N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    {
+        let mut message = self.packet.data;
+        let mut attribution = self.attribution;
+        let mut result = Attribution {
+            hold_times: Vec::new(),
+            misbehaving: None,
+            failure: None,
+        };
+
+        for (position, shared_secret) in data.shared_secrets.iter().enumerate() {
+            if position == Self::MAX_HOPS {
+                break;
+            }
+            if position != 0 {
+                attribution = Self::unshift(attribution.as_ref());
+            }
+            Self::obfuscate(shared_secret, attribution.as_mut())?;
+
+            let start = Self::entry(0, position);
+            let hmac = Self::hmac(
+                shared_secret,
+                message.as_ref(),
+                attribution.as_ref(),
+                position,
+            );
+            if !constant_time_eq(&attribution[start..(start + Self::HMAC_LENGTH)], &hmac[..]) {
+                result.misbehaving = Some(position);
+                break;
+            }
+            let mut hold_time = [0; 4];
+            hold_time.copy_from_slice(&attribution[..Self::HOLD_TIME_LENGTH]);
+            result.hold_times.push(u32::from_be_bytes(hold_time));
+
+            ErrorPacket::<B>::obfuscate(shared_secret, message.as_mut())?;
+            if let Some(failure) = ErrorPacket::<B>::unwrap(shared_secret, message.as_ref())? {
+                result.failure = Some((position, failure));
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.packet.to_bytes();
+        bytes.extend_from_slice(self.attribution.as_ref());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProcessError> {
+        if bytes.len() < Self::ATTRIBUTION_LENGTH {
+            return Err(ProcessError::MalformedPacket);
+        }
+        let (packet, attribution) = bytes.split_at(bytes.len() - Self::ATTRIBUTION_LENGTH);
+        Ok(AttributableErrorPacket {
+            packet: ErrorPacket::from_bytes(packet)?,
+            attribution: attribution.to_vec(),
+        })
+    }
+}
+
+mod implementations {
+    use super::{ErrorPacket, AttributableErrorPacket, Sphinx};
+    use core::fmt;
+
+    impl<B> fmt::Debug for ErrorPacket<B>
+    where
+        B: Sphinx,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("ErrorPacket")
+                .field("data", &self.data)
+                .finish()
+        }
+    }
+
+    impl<B> PartialEq for ErrorPacket<B>
+    where
+        B: Sphinx,
+    {
+        fn eq(&self, other: &Self) -> bool {
+            self.data.eq(&other.data)
+        }
+    }
+
+    impl<B> Eq for ErrorPacket<B> where B: Sphinx {}
+
+    impl<B> fmt::Debug for AttributableErrorPacket<B>
+    where
+        B: Sphinx,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("AttributableErrorPacket")
+                .field("packet", &self.packet)
+                .field("attribution", &self.attribution)
+                .finish()
+        }
+    }
+
+    impl<B> PartialEq for AttributableErrorPacket<B>
+    where
+        B: Sphinx,
+    {
+        fn eq(&self, other: &Self) -> bool {
+            self.packet.eq(&other.packet) && self.attribution.eq(&other.attribution)
+        }
+    }
+
+    impl<B> Eq for AttributableErrorPacket<B> where B: Sphinx {}
+}
@@ -0,0 +1,141 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret};
+use super::packet::{AuthenticatedMessage, LocalData, Processed, ProcessError};
+use super::replay::{ReplayCache, ReplayError};
+
+use generic_array::ArrayLength;
+use rac::{LineValid, Curve};
+use digest::{Input, FixedOutput};
+use alloc::{vec::Vec, collections::BTreeMap};
+
+// the epoch of the key that opened the packet, the next ephemeral key and the packet itself
+pub type EpochProcessed<B, L, N, P> = (u64, <B as Sphinx>::AsymmetricKey, Processed<B, L, N, P>);
+
+// the secret keys of a node by epoch, a packet may be built for the current key,
+// the previous one, or the next one if it is already published
+pub struct NodeKeyring<A>
+where
+    A: Curve,
+{
+    current: u64,
+    keys: BTreeMap<u64, A::Scalar>,
+}
+
+impl<A> NodeKeyring<A>
+where
+    A: Curve,
+{
+    pub fn new(epoch: u64, secret_key: A::Scalar) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(epoch, secret_key);
+        NodeKeyring {
+            current: epoch,
+            keys: keys,
+        }
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn insert(&mut self, epoch: u64, secret_key: A::Scalar) {
+        self.keys.insert(epoch, secret_key);
+    }
+
+    pub fn epochs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.keys.keys().cloned()
+    }
+
+    // makes `epoch` current, the keys before the previous epoch retire,
+    // and so do the replay tags inserted under them
+    pub fn rotate<C>(&mut self, epoch: u64, cache: &mut C) -> Result<(), C::Error>
+    where
+        C: ReplayCache,
+    {
+        let oldest = epoch.saturating_sub(1);
+        self.current = epoch;
+        self.keys = self.keys.split_off(&oldest);
+        cache.expire(oldest)
+    }
+
+    // the current key first, then the previous and the next one
+    fn candidates(&self) -> Vec<(u64, &A::Scalar)> {
+        let current = self.current;
+        [
+            Some(current),
+            current.checked_sub(1),
+            current.checked_add(1),
+        ]
+        .iter()
+        .filter_map(|&epoch| epoch)
+        .filter_map(|epoch| self.keys.get(&epoch).map(|key| (epoch, key)))
+        .collect()
+    }
+
+    // finds the key the packet is built for, returns its epoch,
+    // the local data and the public key for the next hop
+    pub fn local<B, L, N, P, T>(
+        &self,
+        packet: &AuthenticatedMessage<B, L, N, P>,
+        public_key: &A,
+        associated_data: T,
+    ) -> Result<(u64, LocalData<A>, A), ProcessError>
+    where
+        B: Sphinx<AsymmetricKey = A>,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<A>>,
+        P: AsMut<[u8]>,
+        T: AsRef<[u8]>,
+    {
+        for (epoch, secret_key) in self.candidates() {
+            let (local, next) = LocalData::next::<B>(secret_key, public_key)?;
+            if packet.verify(associated_data.as_ref(), &local) {
+                return Ok((epoch, local, next));
+            }
+        }
+        Err(ProcessError::MacMismatch)
+    }
+
+    pub fn process<B, L, N, P, T>(
+        &self,
+        packet: AuthenticatedMessage<B, L, N, P>,
+        public_key: &A,
+        associated_data: T,
+    ) -> Result<EpochProcessed<B, L, N, P>, ProcessError>
+    where
+        B: Sphinx<AsymmetricKey = A>,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<A>>,
+        P: AsMut<[u8]>,
+        T: AsRef<[u8]>,
+    {
+        let (epoch, local, next) = self.local(&packet, public_key, associated_data.as_ref())?;
+        let processed = packet.process(associated_data, &local)?;
+        Ok((epoch, next, processed))
+    }
+
+    // the tag is remembered under the epoch of the key, so it expires together with the key
+    pub fn process_with_replay<B, L, N, P, D, T, C>(
+        &self,
+        packet: AuthenticatedMessage<B, L, N, P>,
+        public_key: &A,
+        associated_data: T,
+        cache: &mut C,
+    ) -> Result<EpochProcessed<B, L, N, P>, ReplayError<C::Error>>
+    where
+        B: Sphinx<AsymmetricKey = A>,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<A>>,
+        P: AsMut<[u8]>,
+        D: Default + Input + FixedOutput<OutputSize = <A::Scalar as LineValid>::Length>,
+        T: AsRef<[u8]>,
+        C: ReplayCache,
+    {
+        let (epoch, local, next) = self
+            .local(&packet, public_key, associated_data.as_ref())
+            .map_err(ReplayError::Process)?;
+        let processed =
+            packet.process_with_replay::<D, _, _>(associated_data, &local, epoch, cache)?;
+        Ok((epoch, next, processed))
+    }
+}
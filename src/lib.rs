@@ -1,13 +1,96 @@
 #![forbid(unsafe_code)]
 #![allow(non_shorthand_field_patterns)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-#[cfg(all(test, feature = "serde-support"))]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(all(test, feature = "std", feature = "serde-support"))]
 mod test;
 
 mod path;
 mod sphinx;
+mod lioness;
 mod packet;
+mod reply;
+mod buffer;
+mod codec;
+mod onion;
+mod suites;
+
+// everything below needs a heap
+#[cfg(feature = "alloc")]
+mod replay;
+#[cfg(feature = "alloc")]
+mod variable;
+#[cfg(feature = "alloc")]
+mod failure;
+#[cfg(feature = "alloc")]
+mod blinding;
+#[cfg(feature = "alloc")]
+mod keyring;
+#[cfg(feature = "alloc")]
+mod command;
+#[cfg(all(feature = "alloc", feature = "rayon"))]
+mod batch;
+#[cfg(feature = "alloc")]
+mod builder;
+
+#[cfg(feature = "lightning")]
+mod lightning;
+
+#[cfg(feature = "x25519")]
+mod x25519;
+
+#[cfg(feature = "ristretto")]
+mod ristretto;
 
-pub use self::sphinx::{SharedSecret, Sphinx, PseudoRandomStream};
-pub use self::packet::{AuthenticatedMessage, LocalData, GlobalData, Processed};
+pub use self::path::{Path, PayloadHmac};
+pub use self::sphinx::{
+    SharedSecret, Sphinx, ErrorKeys, BlindingKeys, PseudoRandomStream, PayloadCipher, XorCipher,
+    Wiped,
+};
+pub use self::lioness::Lioness;
+pub use self::packet::{AuthenticatedMessage, LocalData, GlobalData, Processed, ProcessError};
+pub use self::buffer::{PacketBuffer, BufferProcessed};
+pub use self::codec::{CodecError, point_size, encode_point_into, point_from_bytes};
+#[cfg(feature = "alloc")]
+pub use self::codec::point_to_bytes;
+pub use self::onion::{OnionPacket, OnionPacketProcessed};
+pub use self::reply::{ReplyBlock, ReplyDecryptor, SealedReply};
+#[cfg(feature = "alloc")]
+pub use self::replay::{ReplayCache, ReplayError, CacheFull};
+#[cfg(feature = "std")]
+pub use self::replay::{MemoryReplayCache, FileReplayCache};
+#[cfg(feature = "alloc")]
+pub use self::keyring::{NodeKeyring, EpochProcessed};
+#[cfg(feature = "alloc")]
+pub use self::command::RoutingCommand;
+#[cfg(all(feature = "alloc", feature = "rayon"))]
+pub use self::batch::BatchResult;
+#[cfg(feature = "alloc")]
+pub use self::builder::{PacketBuilder, BuildError};
+#[cfg(feature = "alloc")]
+pub use self::blinding::{DataCipher, StreamDataCipher, BlindedHop, BlindedPath, BlindedRelay};
+#[cfg(feature = "alloc")]
+pub use self::failure::{ErrorPacket, AttributableErrorPacket, Attribution};
+#[cfg(feature = "alloc")]
+pub use self::variable::{
+    VariableMessage, VariableProcessed, bigsize_length, write_bigsize, read_bigsize,
+};
+#[cfg(feature = "suite-secp256k1")]
+pub use self::suites::Secp256k1Sha256ChaCha20;
+#[cfg(feature = "suite-x25519-blake2b")]
+pub use self::suites::{X25519Blake2bChaCha20, Blake2b256};
+#[cfg(feature = "suite-x25519-aes")]
+pub use self::suites::{X25519Sha512256Aes256Ctr, Aes256CtrStream};
+#[cfg(feature = "lightning")]
+pub use self::lightning::{
+    LightningSphinx, U1300, MaxHops, OnionHeader, ChaChaPolyCipher, PaymentData, HopPayload,
+    OnionProcessed, Onion,
+};
+#[cfg(feature = "x25519")]
+pub use self::x25519::{X25519Scalar, X25519Point};
+#[cfg(feature = "ristretto")]
+pub use self::ristretto::{Ristretto255Scalar, Ristretto255Point};
 pub use generic_array;
@@ -0,0 +1,334 @@
+use super::sphinx::{PseudoRandomStream, SharedSecret, wipe};
+use super::packet::{GlobalData, LocalData, ProcessError};
+use super::blinding::DataCipher;
+use super::variable::{VariableMessage, VariableProcessed, read_bigsize, write_bigsize};
+use super::suites::Secp256k1Sha256ChaCha20;
+
+use generic_array::{
+    GenericArray,
+    typenum::{Sum, U27, U276, U1024},
+};
+use keystream::KeyStream;
+use rac::{LineValid, Curve};
+use crypto_mac::Mac;
+use secp256k1::{PublicKey, SecretKey};
+use hmac::Hmac;
+use sha2::Sha256;
+use chacha::ChaCha;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, NewAead},
+};
+use alloc::{boxed::Box, vec::Vec};
+
+pub type LightningSphinx = Secp256k1Sha256ChaCha20;
+
+pub type U1300 = Sum<U1024, U276>;
+
+// the same limit as lnd uses, 1300 bytes do not fit more hops in practice
+pub type MaxHops = U27;
+
+pub type OnionHeader = VariableMessage<LightningSphinx, U1300, [u8; 0]>;
+
+// chacha20-poly1305 keyed by `rho` with zero nonce, the encrypted recipient data of BOLT #4
+pub struct ChaChaPolyCipher;
+
+impl ChaChaPolyCipher {
+    fn cipher(shared: &SharedSecret<PublicKey>) -> ChaCha20Poly1305 {
+        let mut collector = Hmac::<Sha256>::new_varkey(b"rho").unwrap();
+        collector.input(shared);
+        let mut key = [0; 32];
+        key.copy_from_slice(collector.result().code().as_ref());
+        let cipher = ChaCha20Poly1305::new(&Key::from(key));
+        wipe(key.as_mut());
+        cipher
+    }
+}
+
+impl DataCipher<PublicKey> for ChaChaPolyCipher {
+    fn seal(shared: &SharedSecret<PublicKey>, data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+        Self::cipher(shared)
+            .encrypt(&Nonce::from([0; 12]), data)
+            .map_err(|_| ProcessError::MalformedHopData)
+    }
+
+    fn open(shared: &SharedSecret<PublicKey>, data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+        Self::cipher(shared)
+            .decrypt(&Nonce::from([0; 12]), data)
+            .map_err(|_| ProcessError::MacMismatch)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PaymentData {
+    pub payment_secret: [u8; 32],
+    pub total_msat: u64,
+}
+
+// the tlv stream of a hop, records this crate does not know are kept as is
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct HopPayload {
+    pub amount_to_forward: Option<u64>,
+    pub outgoing_cltv_value: Option<u32>,
+    pub short_channel_id: Option<u64>,
+    pub payment_data: Option<PaymentData>,
+    pub encrypted_recipient_data: Option<Vec<u8>>,
+    pub current_path_key: Option<PublicKey>,
+    pub records: Vec<(u64, Vec<u8>)>,
+}
+
+impl HopPayload {
+    const AMOUNT_TO_FORWARD: u64 = 2;
+    const OUTGOING_CLTV_VALUE: u64 = 4;
+    const SHORT_CHANNEL_ID: u64 = 6;
+    const PAYMENT_DATA: u64 = 8;
+    const ENCRYPTED_RECIPIENT_DATA: u64 = 10;
+    const CURRENT_PATH_KEY: u64 = 12;
+
+    // big endian without leading zeros
+    fn read_truncated(value: &[u8], size: usize) -> Result<u64, ProcessError> {
+        if value.len() > size || value.first() == Some(&0) {
+            Err(ProcessError::MalformedHopData)
+        } else {
+            Ok(value.iter().fold(0, |a, &x| (a << 8) | (x as u64)))
+        }
+    }
+
+    fn write_truncated(value: u64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let zeros = (value.leading_zeros() / 8) as usize;
+        bytes[zeros..].to_vec()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProcessError> {
+        let mut payload = HopPayload::default();
+        let mut rest = data;
+        let mut last = None;
+        while !rest.is_empty() {
+            let (t, consumed) = read_bigsize(rest)?;
+            rest = &rest[consumed..];
+            let (length, consumed) = read_bigsize(rest)?;
+            rest = &rest[consumed..];
+            // the types must be strictly increasing
+            if last >= Some(t) || length > rest.len() as u64 {
+                return Err(ProcessError::MalformedHopData);
+            }
+            last = Some(t);
+            let (value, tail) = rest.split_at(length as usize);
+            rest = tail;
+
+            match t {
+                Self::AMOUNT_TO_FORWARD => {
+                    payload.amount_to_forward = Some(Self::read_truncated(value, 8)?);
+                },
+                Self::OUTGOING_CLTV_VALUE => {
+                    payload.outgoing_cltv_value = Some(Self::read_truncated(value, 4)? as u32);
+                },
+                Self::SHORT_CHANNEL_ID => {
+                    if value.len() != 8 {
+                        return Err(ProcessError::MalformedHopData);
+                    }
+                    let mut array = [0; 8];
+                    array.copy_from_slice(value);
+                    payload.short_channel_id = Some(u64::from_be_bytes(array));
+                },
+                Self::PAYMENT_DATA => {
+                    if value.len() < 32 {
+                        return Err(ProcessError::MalformedHopData);
+                    }
+                    let mut payment_secret = [0; 32];
+                    payment_secret.copy_from_slice(&value[..32]);
+                    payload.payment_data = Some(PaymentData {
+                        payment_secret: payment_secret,
+                        total_msat: Self::read_truncated(&value[32..], 8)?,
+                    });
+                },
+                Self::ENCRYPTED_RECIPIENT_DATA => {
+                    payload.encrypted_recipient_data = Some(value.to_vec());
+                },
+                Self::CURRENT_PATH_KEY => {
+                    payload.current_path_key = Some(
+                        PublicKey::from_slice(value).map_err(|_| ProcessError::MalformedHopData)?,
+                    );
+                },
+                // it is fine to skip an unknown odd type, but not an even one
+                t if t % 2 == 0 => return Err(ProcessError::MalformedHopData),
+                t => payload.records.push((t, value.to_vec())),
+            }
+        }
+        Ok(payload)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut records = self.records.clone();
+        if let Some(amount) = self.amount_to_forward {
+            records.push((Self::AMOUNT_TO_FORWARD, Self::write_truncated(amount)));
+        }
+        if let Some(cltv) = self.outgoing_cltv_value {
+            records.push((
+                Self::OUTGOING_CLTV_VALUE,
+                Self::write_truncated(cltv as u64),
+            ));
+        }
+        if let Some(short_channel_id) = self.short_channel_id {
+            records.push((
+                Self::SHORT_CHANNEL_ID,
+                short_channel_id.to_be_bytes().to_vec(),
+            ));
+        }
+        if let Some(ref payment_data) = self.payment_data {
+            let mut value = payment_data.payment_secret.to_vec();
+            value.extend_from_slice(Self::write_truncated(payment_data.total_msat).as_ref());
+            records.push((Self::PAYMENT_DATA, value));
+        }
+        if let Some(ref encrypted_recipient_data) = self.encrypted_recipient_data {
+            records.push((
+                Self::ENCRYPTED_RECIPIENT_DATA,
+                encrypted_recipient_data.clone(),
+            ));
+        }
+        if let Some(ref current_path_key) = self.current_path_key {
+            records.push((
+                Self::CURRENT_PATH_KEY,
+                current_path_key.serialize().to_vec(),
+            ));
+        }
+        records.sort_by_key(|&(t, _)| t);
+
+        let mut data = Vec::new();
+        for (t, value) in records {
+            write_bigsize(t, &mut data);
+            write_bigsize(value.len() as u64, &mut data);
+            data.extend_from_slice(value.as_ref());
+        }
+        data
+    }
+}
+
+// the next onion is boxed, it is much larger than the payload of the exit
+#[derive(Debug, Eq, PartialEq)]
+pub enum OnionProcessed {
+    Forward {
+        payload: HopPayload,
+        next: Box<Onion>,
+    },
+    Exit {
+        payload: HopPayload,
+    },
+}
+
+// version || ephemeral public key || hop payloads || hmac
+#[derive(Debug, Eq, PartialEq)]
+pub struct Onion {
+    version: u8,
+    public_key: PublicKey,
+    header: OnionHeader,
+}
+
+impl Onion {
+    pub const VERSION: u8 = 0;
+    pub const SIZE: usize = 1366;
+
+    // the initial routing info is the `pad` stream, as BOLT #4 requires
+    fn padding(session_key: &SecretKey) -> Result<GenericArray<u8, U1300>, ProcessError> {
+        let mut collector = Hmac::<Sha256>::new_varkey(b"pad").unwrap();
+        collector.input(session_key.clone_line().as_ref());
+        let mut padding = GenericArray::default();
+        ChaCha::seed(collector.result().code()).xor_read(padding.as_mut())?;
+        Ok(padding)
+    }
+
+    pub fn new<H, I, T>(
+        session_key: &SecretKey,
+        path: H,
+        payloads: I,
+        associated_data: T,
+    ) -> Result<Self, ProcessError>
+    where
+        H: Iterator<Item = PublicKey>,
+        I: Iterator<Item = Vec<u8>> + DoubleEndedIterator + ExactSizeIterator,
+        T: AsRef<[u8]>,
+    {
+        let (data, public_key) =
+            GlobalData::<_, MaxHops>::new::<_, LightningSphinx>(session_key, path)?;
+        let header = OnionHeader::with_padding(
+            data,
+            Self::padding(session_key)?,
+            associated_data,
+            payloads,
+            [],
+        )?;
+        Ok(Onion {
+            version: Self::VERSION,
+            public_key: public_key,
+            header: header,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.push(self.version);
+        bytes.extend_from_slice(self.public_key.compress().clone_line().as_ref());
+        bytes.extend_from_slice(self.header.routing_info.as_ref());
+        bytes.extend_from_slice(self.header.hmac.as_ref());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProcessError> {
+        if bytes.len() != Self::SIZE {
+            return Err(ProcessError::MalformedPacket);
+        }
+        if bytes[0] != Self::VERSION {
+            return Err(ProcessError::UnknownVersion);
+        }
+
+        let compressed = LineValid::try_clone_array(GenericArray::from_slice(&bytes[1..34]))
+            .map_err(|_| ProcessError::InvalidPoint)?;
+        let public_key =
+            PublicKey::decompress(&compressed).map_err(|_| ProcessError::InvalidPoint)?;
+        Ok(Onion {
+            version: bytes[0],
+            public_key: public_key,
+            header: VariableMessage {
+                routing_info: GenericArray::clone_from_slice(&bytes[34..1334]),
+                hmac: GenericArray::clone_from_slice(&bytes[1334..]),
+                message: [],
+            },
+        })
+    }
+
+    pub fn process<T>(
+        self,
+        associated_data: T,
+        secret_key: &SecretKey,
+    ) -> Result<OnionProcessed, ProcessError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let (local, public_key) = LocalData::next::<LightningSphinx>(secret_key, &self.public_key)?;
+        match self.header.process(associated_data, &local)? {
+            VariableProcessed::Forward {
+                data: data,
+                next: next,
+            } => Ok(OnionProcessed::Forward {
+                payload: HopPayload::decode(data.as_ref())?,
+                next: Box::new(Onion {
+                    version: self.version,
+                    public_key: public_key,
+                    header: next,
+                }),
+            }),
+            VariableProcessed::Exit { data: data, .. } => Ok(OnionProcessed::Exit {
+                payload: HopPayload::decode(data.as_ref())?,
+            }),
+        }
+    }
+}
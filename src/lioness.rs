@@ -0,0 +1,85 @@
+use super::sphinx::{PayloadCipher, PseudoRandomStream, SharedSecret, Wiped, mac, blank_stream, wipe};
+use super::packet::ProcessError;
+
+use generic_array::typenum::Unsigned;
+use keystream::KeyStream;
+use rac::Curve;
+use crypto_mac::Mac;
+use core::marker::PhantomData;
+
+// Lioness wide block cipher built from the stream `S` and the mac `C`,
+// any change of the ciphertext turns the whole plaintext into garbage
+pub struct Lioness<C, S>
+where
+    C: Mac,
+    S: PseudoRandomStream<C::OutputSize> + KeyStream,
+{
+    phantom_data: PhantomData<(C, S)>,
+}
+
+impl<C, S> Lioness<C, S>
+where
+    C: Mac,
+    S: PseudoRandomStream<C::OutputSize> + KeyStream,
+{
+    const TAG_LENGTH: usize = 16;
+
+    fn stream(
+        label: &[u8],
+        shared: &[u8],
+        left: &[u8],
+        right: &mut [u8],
+    ) -> Result<(), ProcessError> {
+        let mut seed = mac::<C>(label, shared);
+        seed.iter_mut().zip(left.iter()).for_each(|(s, l)| *s ^= l);
+        let mut stream = Wiped::new(S::seed(seed.clone()), blank_stream::<C::OutputSize, S>);
+        wipe(seed.as_mut());
+        stream.xor_read(right).map_err(ProcessError::Stream)
+    }
+
+    fn hash(label: &[u8], shared: &[u8], left: &mut [u8], right: &[u8]) {
+        let mut key = mac::<C>(label, shared);
+        let mut h = mac::<C>(&key, right);
+        left.iter_mut().zip(h.iter()).for_each(|(l, h)| *l ^= h);
+        wipe(key.as_mut());
+        wipe(h.as_mut());
+    }
+
+    fn split(payload: &mut [u8]) -> Result<(&mut [u8], &mut [u8]), ProcessError> {
+        let length = C::OutputSize::to_usize();
+        if payload.len() <= length || payload.len() < Self::TAG_LENGTH {
+            Err(ProcessError::PayloadTooShort)
+        } else {
+            Ok(payload.split_at_mut(length))
+        }
+    }
+}
+
+impl<A, C, S> PayloadCipher<A> for Lioness<C, S>
+where
+    A: Curve,
+    C: Mac,
+    S: PseudoRandomStream<C::OutputSize> + KeyStream,
+{
+    fn tag_length() -> usize {
+        Self::TAG_LENGTH
+    }
+
+    fn encrypt(shared: &SharedSecret<A>, payload: &mut [u8]) -> Result<(), ProcessError> {
+        let (left, right) = Self::split(payload)?;
+        Self::stream(b"lioness-1", shared.as_ref(), left, right)?;
+        Self::hash(b"lioness-2", shared.as_ref(), left, right);
+        Self::stream(b"lioness-3", shared.as_ref(), left, right)?;
+        Self::hash(b"lioness-4", shared.as_ref(), left, right);
+        Ok(())
+    }
+
+    fn decrypt(shared: &SharedSecret<A>, payload: &mut [u8]) -> Result<(), ProcessError> {
+        let (left, right) = Self::split(payload)?;
+        Self::hash(b"lioness-4", shared.as_ref(), left, right);
+        Self::stream(b"lioness-3", shared.as_ref(), left, right)?;
+        Self::hash(b"lioness-2", shared.as_ref(), left, right);
+        Self::stream(b"lioness-1", shared.as_ref(), left, right)?;
+        Ok(())
+    }
+}
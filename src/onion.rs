@@ -0,0 +1,263 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret};
+use super::packet::{AuthenticatedMessage, GlobalData, LocalData, Processed, ProcessError};
+use super::codec::{CodecError, check_length, point_size, encode_point_into, point_from_bytes};
+
+use generic_array::{GenericArray, ArrayLength};
+use rac::Curve;
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+
+pub enum OnionPacketProcessed<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]>,
+{
+    Forward {
+        data: GenericArray<u8, L>,
+        next: OnionPacket<B, L, N, P>,
+    },
+    Exit {
+        data: GenericArray<u8, L>,
+        message: P,
+    },
+}
+
+// version || ephemeral public key || routing info || hmac || message,
+// the packet carries its own public key, so a node needs only its secret key to process it
+pub struct OnionPacket<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]>,
+{
+    version: u8,
+    public_key: B::AsymmetricKey,
+    message: AuthenticatedMessage<B, L, N, P>,
+}
+
+impl<B, L, N, P> OnionPacket<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    P: AsMut<[u8]>,
+{
+    pub const VERSION: u8 = 0;
+
+    pub(crate) fn from_parts(
+        public_key: B::AsymmetricKey,
+        message: AuthenticatedMessage<B, L, N, P>,
+    ) -> Self {
+        OnionPacket {
+            version: Self::VERSION,
+            public_key: public_key,
+            message: message,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn public_key(&self) -> &B::AsymmetricKey {
+        &self.public_key
+    }
+
+    pub fn message(&self) -> &AuthenticatedMessage<B, L, N, P> {
+        &self.message
+    }
+
+    pub fn header_size() -> usize
+    where
+        P: AsRef<[u8]>,
+    {
+        1 + point_size::<B::AsymmetricKey>()
+            + AuthenticatedMessage::<B, L, N, P>::body_header_size()
+    }
+
+    pub fn encoded_size(&self) -> usize
+    where
+        P: AsRef<[u8]>,
+    {
+        Self::header_size() + self.message.message.as_ref().len()
+    }
+
+    pub fn encode_into(&self, buffer: &mut [u8]) -> Result<(), CodecError>
+    where
+        P: AsRef<[u8]>,
+    {
+        check_length(self.encoded_size(), buffer.len())?;
+        let (version, rest) = buffer.split_at_mut(1);
+        let (public_key, rest) = rest.split_at_mut(point_size::<B::AsymmetricKey>());
+        version[0] = self.version;
+        encode_point_into(&self.public_key, public_key)?;
+        self.message.encode_body_into(rest)
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&self) -> Vec<u8>
+    where
+        P: AsRef<[u8]>,
+    {
+        let mut bytes = vec![0; self.encoded_size()];
+        self.encode_into(bytes.as_mut())
+            .expect("the buffer has the right size");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError>
+    where
+        P: AsRef<[u8]> + Default,
+    {
+        let message_size = P::default().as_ref().len();
+        check_length(Self::header_size() + message_size, bytes.len())?;
+        if bytes[0] != Self::VERSION {
+            return Err(CodecError::UnknownVersion(bytes[0]));
+        }
+
+        let (public_key, rest) = bytes[1..].split_at(point_size::<B::AsymmetricKey>());
+        Ok(OnionPacket {
+            version: bytes[0],
+            public_key: point_from_bytes(public_key)?,
+            message: AuthenticatedMessage::from_body_bytes(rest)?,
+        })
+    }
+}
+
+impl<B, L, N, P> OnionPacket<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    pub fn new<T, H, I>(
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+        path: H,
+        associated_data: T,
+        payloads: I,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        B::AsymmetricKey: Clone,
+        <B::AsymmetricKey as Curve>::Scalar: Clone,
+        T: AsRef<[u8]>,
+        H: Iterator<Item = B::AsymmetricKey>,
+        I: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let (data, public_key) = GlobalData::<_, N>::new::<_, B>(session_key, path)?;
+        let message = AuthenticatedMessage::new(data, associated_data, payloads, message)?;
+        Ok(Self::from_parts(public_key, message))
+    }
+
+    // see `AuthenticatedMessage::with_padding`
+    pub fn with_padding<T, H, I>(
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+        path: H,
+        associated_data: T,
+        payloads: I,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        B::AsymmetricKey: Clone,
+        <B::AsymmetricKey as Curve>::Scalar: Clone,
+        T: AsRef<[u8]>,
+        H: Iterator<Item = B::AsymmetricKey>,
+        I: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let (data, public_key) = GlobalData::<_, N>::new::<_, B>(session_key, path)?;
+        let message = AuthenticatedMessage::with_padding(
+            data,
+            session_key,
+            associated_data,
+            payloads,
+            message,
+        )?;
+        Ok(Self::from_parts(public_key, message))
+    }
+
+    // derives the shared secret, verifies the hmac and unwraps one layer,
+    // the next packet already holds the blinded public key for the next hop
+    pub fn process<T>(
+        self,
+        secret_key: &<B::AsymmetricKey as Curve>::Scalar,
+        associated_data: T,
+    ) -> Result<OnionPacketProcessed<B, L, N, P>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let (local, public_key) = LocalData::next::<B>(secret_key, &self.public_key)?;
+        match self.message.process(associated_data, &local)? {
+            Processed::Forward {
+                data: data,
+                next: next,
+            } => Ok(OnionPacketProcessed::Forward {
+                data: data,
+                next: OnionPacket {
+                    version: self.version,
+                    public_key: public_key,
+                    message: next,
+                },
+            }),
+            Processed::Exit {
+                data: data,
+                message: message,
+            } => Ok(OnionPacketProcessed::Exit {
+                data: data,
+                message: message,
+            }),
+        }
+    }
+}
+
+mod implementations {
+    use super::{OnionPacket, Sphinx, PayloadHmac};
+    use generic_array::ArrayLength;
+    use core::fmt;
+
+    impl<B, L, N, P> fmt::Debug for OnionPacket<B, L, N, P>
+    where
+        B: Sphinx,
+        B::AsymmetricKey: fmt::Debug,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+        P: fmt::Debug + AsMut<[u8]>,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("OnionPacket")
+                .field("version", &self.version)
+                .field("public_key", &self.public_key)
+                .field("message", &self.message)
+                .finish()
+        }
+    }
+
+    impl<B, L, N, P> PartialEq for OnionPacket<B, L, N, P>
+    where
+        B: Sphinx,
+        B::AsymmetricKey: PartialEq,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+        P: PartialEq + AsMut<[u8]>,
+    {
+        fn eq(&self, other: &Self) -> bool {
+            self.version.eq(&other.version)
+                && self.public_key.eq(&other.public_key)
+                && self.message.eq(&other.message)
+        }
+    }
+
+    impl<B, L, N, P> Eq for OnionPacket<B, L, N, P>
+    where
+        B: Sphinx,
+        B::AsymmetricKey: PartialEq,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+        P: PartialEq + AsMut<[u8]>,
+    {
+    }
+}
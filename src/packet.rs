@@ -1,36 +1,147 @@
 use super::path::{PayloadHmac, Path};
-use super::sphinx::{Sphinx, SharedSecret};
+use super::sphinx::{
+    Sphinx, SharedSecret, PayloadCipher, wipe, constant_time_eq, constant_time_is_zero,
+};
 
 use generic_array::{GenericArray, ArrayLength};
 use rac::{LineValid, Curve};
 use keystream::SeekableKeyStream;
 use digest::{Input, FixedOutput};
 
+// the routing info together with the hmac of the first hop
+pub(crate) type Header<B, L, N> = (
+    Path<L, <B as Sphinx>::MacLength, N>,
+    GenericArray<u8, <B as Sphinx>::MacLength>,
+);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProcessError {
+    MacMismatch,
+    Stream(keystream::Error),
+    InvalidScalar,
+    InvalidPoint,
+    PathTooLong,
+    Replay,
+    PayloadTooShort,
+    MessageTooLong,
+    PayloadCorrupted,
+    MalformedHopData,
+    MalformedPacket,
+    UnknownVersion,
+    EmptyPath,
+    UnknownCommand(u8),
+    CommandsTooLong,
+}
+
+impl From<keystream::Error> for ProcessError {
+    fn from(e: keystream::Error) -> Self {
+        ProcessError::Stream(e)
+    }
+}
+
+// the integrity tag goes in front of the message, which is shifted to make room,
+// so the last `tag_length` bytes of the message must be free, nothing is overwritten
+pub(crate) fn prepend_tag<B>(message: &mut [u8]) -> Result<(), ProcessError>
+where
+    B: Sphinx,
+{
+    let tag_length = B::PayloadCipher::tag_length();
+    if message.len() < tag_length {
+        return Err(ProcessError::PayloadTooShort);
+    }
+    let end = message.len() - tag_length;
+    if message[end..].iter().any(|&x| x != 0) {
+        return Err(ProcessError::MessageTooLong);
+    }
+    message.copy_within(..end, tag_length);
+    message[..tag_length].iter_mut().for_each(|x| *x = 0);
+    Ok(())
+}
+
+// checks the tag and shifts the message back, the exit gets what the sender put in
+pub(crate) fn strip_tag<B>(message: &mut [u8]) -> Result<(), ProcessError>
+where
+    B: Sphinx,
+{
+    let tag_length = B::PayloadCipher::tag_length();
+    if message.len() < tag_length {
+        return Err(ProcessError::PayloadTooShort);
+    }
+    if !constant_time_is_zero(&message[..tag_length]) {
+        return Err(ProcessError::PayloadCorrupted);
+    }
+    let end = message.len() - tag_length;
+    message.copy_within(tag_length.., 0);
+    message[end..].iter_mut().for_each(|x| *x = 0);
+    Ok(())
+}
+
+// the exit finds either the zero hmac or, in a padded packet, its own exit marker,
+// both are checked, so the timing does not tell which kind of packet it was
+pub(crate) fn is_exit<B>(shared: &SharedSecret<B::AsymmetricKey>, hmac: &[u8]) -> bool
+where
+    B: Sphinx,
+{
+    let zero = constant_time_is_zero(hmac);
+    let marker = constant_time_eq(B::exit_marker(shared).as_ref(), hmac);
+    zero | marker
+}
+
+// the key exchange, a result with the all zero encoding is rejected, it is the identity
+// of the backends that have one, so the secret would not depend on the key at all
+pub(crate) fn exchange<A>(public_key: &A, secret_key: &A::Scalar) -> Result<A, ProcessError>
+where
+    A: Curve,
+{
+    let point = public_key.exp_ec(secret_key);
+    if constant_time_is_zero(point.compress().clone_line().as_ref()) {
+        Err(ProcessError::InvalidPoint)
+    } else {
+        Ok(point)
+    }
+}
+
 pub struct LocalData<A>
 where
     A: Curve,
 {
-    pub shared_secret: SharedSecret<A>,
+    pub(crate) shared_secret: SharedSecret<A>,
+}
+
+// the shared secrets are wiped on drop, so they are not handed out by value
+impl<A> Drop for LocalData<A>
+where
+    A: Curve,
+{
+    fn drop(&mut self) {
+        wipe(self.shared_secret.as_mut());
+    }
 }
 
 impl<A> LocalData<A>
 where
     A: Curve,
 {
-    pub fn next<B>(secret_key: &A::Scalar, this: &A) -> (Self, A)
+    pub fn next<B>(secret_key: &A::Scalar, this: &A) -> Result<(Self, A), ProcessError>
     where
         B: Sphinx<AsymmetricKey = A>,
     {
-        let shared_secret = B::tau(this.exp_ec(secret_key));
-        // safe to unwrap because array is result if hashing
-        let blinding = A::Scalar::try_clone_array(&B::blinding(this, &shared_secret)).unwrap();
+        let shared_secret = B::tau(exchange(this, secret_key)?);
+        let mut blinding_bytes = B::blinding(this, &shared_secret);
+        let blinding = A::Scalar::try_clone_array(&blinding_bytes);
+        wipe(blinding_bytes.as_mut());
+        let blinding = blinding.map_err(|_| ProcessError::InvalidScalar)?;
         let next = this.exp_ec(&blinding);
-        (
+        Ok((
             LocalData {
                 shared_secret: shared_secret,
             },
             next,
-        )
+        ))
+    }
+
+    pub fn shared_secret(&self) -> &SharedSecret<A> {
+        &self.shared_secret
     }
 
     pub fn digest<D>(&self) -> Self
@@ -48,7 +159,29 @@ where
     A: Curve,
     N: ArrayLength<SharedSecret<A>>,
 {
-    pub shared_secrets: GenericArray<SharedSecret<A>, N>,
+    pub(crate) shared_secrets: GenericArray<SharedSecret<A>, N>,
+}
+
+impl<A, N> GlobalData<A, N>
+where
+    A: Curve,
+    N: ArrayLength<SharedSecret<A>>,
+{
+    pub fn shared_secrets(&self) -> &GenericArray<SharedSecret<A>, N> {
+        &self.shared_secrets
+    }
+}
+
+impl<A, N> Drop for GlobalData<A, N>
+where
+    A: Curve,
+    N: ArrayLength<SharedSecret<A>>,
+{
+    fn drop(&mut self) {
+        self.shared_secrets
+            .iter_mut()
+            .for_each(|shared_secret| wipe(shared_secret.as_mut()));
+    }
 }
 
 impl<A, N> GlobalData<A, N>
@@ -57,7 +190,7 @@ where
     A::Scalar: Clone,
     N: ArrayLength<SharedSecret<A>>,
 {
-    pub fn new<H, B>(session_key: &A::Scalar, path: H) -> (Self, A)
+    pub fn new<H, B>(session_key: &A::Scalar, path: H) -> Result<(Self, A), ProcessError>
     where
         H: Iterator<Item = B::AsymmetricKey>,
         B: Sphinx<AsymmetricKey = A>,
@@ -65,35 +198,34 @@ where
         use rac::Scalar;
 
         let public_key = A::base().exp_ec(session_key);
-
-        let initial = (
-            Vec::with_capacity(N::to_usize()),
-            session_key.clone(),
-            public_key.clone(),
-        );
-
-        let (shared_secrets, _, _) =
-            path.fold(initial, |(mut s, mut secret, public), path_point| {
-                let shared_secret = B::tau(path_point.exp_ec(&secret));
-                let blinding = B::blinding(&public, &shared_secret);
-                // safe to unwrap because the array is result of hashing
-                let blinding = <A::Scalar as LineValid>::try_clone_array(&blinding).unwrap();
-                // safe to unwrap because the scalar is trusted
-                secret = secret.mul_ff(&blinding).unwrap();
-                let public = A::base().exp_ec(&secret);
-
-                s.push(shared_secret);
-                (s, secret, public)
-            });
-
-        let mut shared_secrets_array = GenericArray::default();
-        shared_secrets_array[0..shared_secrets.len()].clone_from_slice(shared_secrets.as_slice());
-        (
-            GlobalData {
-                shared_secrets: shared_secrets_array,
-            },
-            public_key,
-        )
+        let mut path = path;
+
+        // the secrets go straight into the array, no intermediate copy is left behind
+        let mut data = GlobalData {
+            shared_secrets: GenericArray::default(),
+        };
+        let shared_secrets = &mut data.shared_secrets;
+        let initial = (0, session_key.clone(), public_key.clone());
+
+        path.try_fold(initial, |(i, secret, public), path_point| {
+            if i == N::to_usize() {
+                return Err(ProcessError::PathTooLong);
+            }
+            let shared_secret = B::tau(exchange(&path_point, &secret)?);
+            let mut blinding_bytes = B::blinding(&public, &shared_secret);
+            let blinding = <A::Scalar as LineValid>::try_clone_array(&blinding_bytes);
+            wipe(blinding_bytes.as_mut());
+            let blinding = blinding.map_err(|_| ProcessError::InvalidScalar)?;
+            let secret = secret
+                .mul_ff(&blinding)
+                .map_err(|_| ProcessError::InvalidScalar)?;
+            let public = A::base().exp_ec(&secret);
+
+            shared_secrets[i] = shared_secret;
+            Ok((i + 1, secret, public))
+        })?;
+
+        Ok((data, public_key))
     }
 
     pub fn digest<D>(&self) -> Self
@@ -135,9 +267,9 @@ where
     N: ArrayLength<PayloadHmac<L, B::MacLength>>,
     P: AsMut<[u8]>,
 {
-    routing_info: Path<L, B::MacLength, N>,
-    hmac: GenericArray<u8, B::MacLength>,
-    message: P,
+    pub(crate) routing_info: Path<L, B::MacLength, N>,
+    pub(crate) hmac: GenericArray<u8, B::MacLength>,
+    pub(crate) message: P,
 }
 
 impl<B, L, N, P> AuthenticatedMessage<B, L, N, P>
@@ -152,98 +284,227 @@ where
         associated_data: T,
         payloads: H,
         message: P,
-    ) -> Self
+    ) -> Result<Self, ProcessError>
     where
         T: AsRef<[u8]>,
         H: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
     {
-        use keystream::KeyStream;
+        let shared_secrets = &data.shared_secrets;
+        let length = payloads.len();
+        let (routing_info, hmac) = Self::header(shared_secrets, associated_data, payloads)?;
 
-        let GlobalData {
-            shared_secrets: shared_secrets,
-        } = data;
-        let mut hmac = GenericArray::default();
-        let mut routing_info = Path::<L, B::MacLength, N>::new();
         let mut message = message;
-
-        let length = payloads.len();
-        for i in 0..length {
-            let mut s = B::rho(&shared_secrets[i]);
-            let size = PayloadHmac::<L, B::MacLength>::size();
-            s.seek_to((size * (Path::<L, B::MacLength, N>::size() - i)) as _)
-                .unwrap();
-            let start = Path::<L, B::MacLength, N>::size() - length;
-            routing_info.as_mut()[start..(start + i + 1)]
-                .iter_mut()
-                .for_each(|x| *x ^= &mut s);
+        prepend_tag::<B>(message.as_mut())?;
+        for index in (0..length).rev() {
+            B::PayloadCipher::encrypt(&shared_secrets[index], message.as_mut())?;
         }
 
-        payloads.enumerate().rev().for_each(|(index, payload)| {
-            routing_info.push(PayloadHmac {
-                data: payload,
-                hmac: hmac.clone(),
-            });
+        Ok(AuthenticatedMessage {
+            routing_info: routing_info,
+            hmac: hmac,
+            message: message,
+        })
+    }
 
-            let mut stream = B::rho(&shared_secrets[index]);
-            routing_info ^= &mut stream;
+    // the same as `new`, but the routing info beyond the route is the `pad` stream
+    // of the session key instead of zeros, and the exit is told by its exit marker,
+    // so neither the tail of the routing info nor the last hmac is all zero
+    pub fn with_padding<T, H>(
+        data: GlobalData<B::AsymmetricKey, N>,
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+        associated_data: T,
+        payloads: H,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        T: AsRef<[u8]>,
+        H: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let shared_secrets = &data.shared_secrets;
+        let length = payloads.len();
+        if length == 0 {
+            return Err(ProcessError::EmptyPath);
+        }
+        if length > Path::<L, B::MacLength, N>::size() {
+            return Err(ProcessError::PathTooLong);
+        }
 
-            let mut stream = B::pi(&shared_secrets[index]);
-            stream.xor_read(message.as_mut()).unwrap();
+        let mut key = session_key.clone_line();
+        let mut stream = B::pad(&key);
+        wipe(key.as_mut());
+        let mut padding = Path::<L, B::MacLength, N>::new();
+        padding.xor(&mut stream)?;
+        let exit = B::exit_marker(&shared_secrets[length - 1]);
+        let (routing_info, hmac) =
+            Self::header_with(padding, exit, shared_secrets, associated_data, payloads)?;
 
-            let mu = B::mu(&shared_secrets[index]);
-            let mu = routing_info
-                .as_ref()
-                .iter()
-                .fold(mu, |mu, hop| B::chain(B::chain(mu, &hop.data), &hop.hmac));
-            let mu = B::chain(mu, associated_data.as_ref());
-            hmac = B::output(mu);
-        });
+        let mut message = message;
+        prepend_tag::<B>(message.as_mut())?;
+        for index in (0..length).rev() {
+            B::PayloadCipher::encrypt(&shared_secrets[index], message.as_mut())?;
+        }
 
-        AuthenticatedMessage {
+        Ok(AuthenticatedMessage {
             routing_info: routing_info,
             hmac: hmac,
             message: message,
+        })
+    }
+
+    pub(crate) fn header<T, H>(
+        shared_secrets: &GenericArray<SharedSecret<B::AsymmetricKey>, N>,
+        associated_data: T,
+        payloads: H,
+    ) -> Result<Header<B, L, N>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+        H: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        Self::header_with(
+            Path::new(),
+            GenericArray::default(),
+            shared_secrets,
+            associated_data,
+            payloads,
+        )
+    }
+
+    // `padding` is the initial routing info and `exit` is the hmac the last hop finds,
+    // the slots the filler goes to are cleared first
+    fn header_with<T, H>(
+        padding: Path<L, B::MacLength, N>,
+        exit: GenericArray<u8, B::MacLength>,
+        shared_secrets: &GenericArray<SharedSecret<B::AsymmetricKey>, N>,
+        associated_data: T,
+        payloads: H,
+    ) -> Result<Header<B, L, N>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+        H: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let mut hmac = exit;
+        let mut routing_info = padding;
+
+        let length = payloads.len();
+        if length > Path::<L, B::MacLength, N>::size() {
+            return Err(ProcessError::PathTooLong);
+        }
+        let start = Path::<L, B::MacLength, N>::size() - length;
+        routing_info.as_mut()[start..]
+            .iter_mut()
+            .for_each(|x| *x = PayloadHmac::default());
+        for i in 0..length {
+            let mut s = B::rho(&shared_secrets[i]);
+            let size = PayloadHmac::<L, B::MacLength>::size();
+            s.seek_to((size * (Path::<L, B::MacLength, N>::size() - i)) as _)?;
+            routing_info.as_mut()[start..(start + i + 1)]
+                .iter_mut()
+                .try_for_each(|x| x.xor(&mut s))?;
         }
+
+        payloads.enumerate().rev().try_for_each(
+            |(index, payload)| -> Result<(), ProcessError> {
+                routing_info.push(PayloadHmac {
+                    data: payload,
+                    hmac: hmac.clone(),
+                });
+
+                let mut stream = B::rho(&shared_secrets[index]);
+                routing_info.xor(&mut stream)?;
+
+                let mu = B::mu(&shared_secrets[index]);
+                let mu = routing_info
+                    .as_ref()
+                    .iter()
+                    .fold(mu, |mu, hop| B::chain(B::chain(mu, &hop.data), &hop.hmac));
+                let mu = B::chain(mu, associated_data.as_ref());
+                hmac = B::output(mu);
+                Ok(())
+            },
+        )?;
+
+        Ok((routing_info, hmac))
     }
 
     pub fn process<T>(
         self,
         associated_data: T,
         local: &LocalData<B::AsymmetricKey>,
-    ) -> Result<Processed<B, L, N, P>, ()>
+    ) -> Result<Processed<B, L, N, P>, ProcessError>
     where
         T: AsRef<[u8]>,
     {
-        use keystream::KeyStream;
-
-        let (mut routing_info, hmac_received, mut message) =
-            (self.routing_info, self.hmac, self.message);
+        let mut processed = self.peel(associated_data, &local.shared_secret)?;
+        match processed {
+            Processed::Forward { ref mut next, .. } => {
+                B::PayloadCipher::decrypt(&local.shared_secret, next.message.as_mut())?;
+            },
+            Processed::Exit {
+                ref mut message, ..
+            } => {
+                B::PayloadCipher::decrypt(&local.shared_secret, message.as_mut())?;
+                strip_tag::<B>(message.as_mut())?;
+            },
+        }
+        Ok(processed)
+    }
 
-        let mu = B::mu(&local.shared_secret);
-        let mu = routing_info
+    fn hmac<T>(
+        &self,
+        associated_data: T,
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+    ) -> GenericArray<u8, B::MacLength>
+    where
+        T: AsRef<[u8]>,
+    {
+        let mu = B::mu(shared_secret);
+        let mu = self
+            .routing_info
             .as_ref()
             .iter()
             .fold(mu, |mu, hop| B::chain(B::chain(mu, &hop.data), &hop.hmac));
         let mu = B::chain(mu, associated_data.as_ref());
-        let hmac = B::output(mu);
+        B::output(mu)
+    }
+
+    // checks whether the packet is meant for the key, without processing it
+    pub fn verify<T>(&self, associated_data: T, local: &LocalData<B::AsymmetricKey>) -> bool
+    where
+        T: AsRef<[u8]>,
+    {
+        constant_time_eq(
+            self.hmac(associated_data, &local.shared_secret).as_ref(),
+            self.hmac.as_ref(),
+        )
+    }
+
+    // verifies the hmac and unwraps one layer of the routing info, the message is untouched
+    pub(crate) fn peel<T>(
+        self,
+        associated_data: T,
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+    ) -> Result<Processed<B, L, N, P>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let hmac = self.hmac(associated_data, shared_secret);
+        let (mut routing_info, hmac_received, message) =
+            (self.routing_info, self.hmac, self.message);
 
-        if hmac_received != hmac {
-            Err(())
+        if !constant_time_eq(hmac_received.as_ref(), hmac.as_ref()) {
+            Err(ProcessError::MacMismatch)
         } else {
-            let mut stream = B::rho(&local.shared_secret);
+            let mut stream = B::rho(shared_secret);
             let mut item = routing_info.pop();
-            item ^= &mut stream;
-            routing_info ^= &mut stream;
-
-            let mut stream = B::pi(&local.shared_secret);
-            stream.xor_read(message.as_mut()).unwrap();
+            item.xor(&mut stream)?;
+            routing_info.xor(&mut stream)?;
 
             let PayloadHmac {
                 data: item_data,
                 hmac: item_hmac,
             } = item;
 
-            if item_hmac == GenericArray::default() {
+            if is_exit::<B>(shared_secret, item_hmac.as_ref()) {
                 Ok(Processed::Exit {
                     data: item_data,
                     message: message,
@@ -270,8 +531,8 @@ mod serde_m {
 
     use generic_array::{GenericArray, ArrayLength};
     use serde::{Serialize, Serializer, Deserialize, Deserializer};
-    use std::marker::PhantomData;
-    use std::fmt;
+    use core::marker::PhantomData;
+    use core::fmt;
 
     impl<B, L, N, P> Serialize for AuthenticatedMessage<B, L, N, P>
     where
@@ -363,10 +624,37 @@ mod serde_m {
 }
 
 mod implementations {
-    use super::{AuthenticatedMessage, Sphinx, PayloadHmac, LocalData};
+    use super::{AuthenticatedMessage, Sphinx, PayloadHmac, LocalData, ProcessError};
     use generic_array::ArrayLength;
     use rac::Curve;
-    use std::fmt;
+    use core::fmt;
+
+    impl fmt::Display for ProcessError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ProcessError::MacMismatch => write!(f, "hmac mismatch"),
+                ProcessError::Stream(ref e) => write!(f, "key stream error: {:?}", e),
+                ProcessError::InvalidScalar => write!(f, "invalid scalar"),
+                ProcessError::InvalidPoint => write!(f, "invalid point"),
+                ProcessError::PathTooLong => write!(f, "path is too long"),
+                ProcessError::Replay => write!(f, "packet is replayed"),
+                ProcessError::PayloadTooShort => write!(f, "payload is too short"),
+                ProcessError::MessageTooLong => write!(f, "message leaves no room for the tag"),
+                ProcessError::PayloadCorrupted => write!(f, "payload is corrupted"),
+                ProcessError::MalformedHopData => write!(f, "malformed hop data"),
+                ProcessError::MalformedPacket => write!(f, "malformed packet"),
+                ProcessError::UnknownVersion => write!(f, "unknown packet version"),
+                ProcessError::EmptyPath => write!(f, "path is empty"),
+                ProcessError::UnknownCommand(tag) => write!(f, "unknown routing command {}", tag),
+                ProcessError::CommandsTooLong => {
+                    write!(f, "routing commands do not fit the hop data")
+                },
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for ProcessError {}
 
     impl<B, L, N, P> fmt::Debug for AuthenticatedMessage<B, L, N, P>
     where
@@ -1,6 +1,6 @@
 use generic_array::{GenericArray, ArrayLength};
-use std::ops::BitXorAssign;
-use keystream::KeyStream;
+use keystream::{KeyStream, Error};
+use core::mem;
 
 #[cfg(feature = "serde-support")]
 use serde::{Serialize, Deserialize};
@@ -63,17 +63,13 @@ where
     pub fn size() -> usize {
         L::to_usize() + M::to_usize()
     }
-}
 
-impl<'a, L, M, I> BitXorAssign<&'a mut I> for PayloadHmac<L, M>
-where
-    L: ArrayLength<u8>,
-    M: ArrayLength<u8>,
-    I: KeyStream,
-{
-    fn bitxor_assign(&mut self, rhs: &'a mut I) {
-        rhs.xor_read(self.data.as_mut_slice()).unwrap();
-        rhs.xor_read(self.hmac.as_mut_slice()).unwrap();
+    pub fn xor<I>(&mut self, stream: &mut I) -> Result<(), Error>
+    where
+        I: KeyStream,
+    {
+        stream.xor_read(self.data.as_mut_slice())?;
+        stream.xor_read(self.hmac.as_mut_slice())
     }
 }
 
@@ -110,62 +106,62 @@ where
         }
     }
 
+    // the items are rotated in place rather than cloned one by one
     pub fn push(&mut self, item: PayloadHmac<L, M>) {
-        for i in (1..Self::size()).rev() {
-            self.raw[i] = self.raw[i - 1].clone();
-        }
+        self.raw.as_mut_slice().rotate_right(1);
         self.raw[0] = item;
     }
 
     pub fn pop(&mut self) -> PayloadHmac<L, M> {
-        let item = self.raw[0].clone();
+        self.raw.as_mut_slice().rotate_left(1);
+        mem::take(&mut self.raw[Self::size() - 1])
+    }
 
-        for i in 1..Self::size() {
-            self.raw[i - 1] = self.raw[i].clone();
-        }
-        self.raw[Self::size() - 1] = PayloadHmac::default();
-        item
+    pub fn xor<I>(&mut self, stream: &mut I) -> Result<(), Error>
+    where
+        I: KeyStream,
+    {
+        self.raw.iter_mut().try_for_each(|x| x.xor(stream))
     }
 }
 
-impl<L, M, N> AsRef<[PayloadHmac<L, M>]> for Path<L, M, N>
+impl<L, M, N> Default for Path<L, M, N>
 where
     L: ArrayLength<u8>,
     M: ArrayLength<u8>,
     N: ArrayLength<PayloadHmac<L, M>>,
 {
-    fn as_ref(&self) -> &[PayloadHmac<L, M>] {
-        self.raw.as_ref()
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<L, M, N> AsMut<[PayloadHmac<L, M>]> for Path<L, M, N>
+impl<L, M, N> AsRef<[PayloadHmac<L, M>]> for Path<L, M, N>
 where
     L: ArrayLength<u8>,
     M: ArrayLength<u8>,
     N: ArrayLength<PayloadHmac<L, M>>,
 {
-    fn as_mut(&mut self) -> &mut [PayloadHmac<L, M>] {
-        self.raw.as_mut()
+    fn as_ref(&self) -> &[PayloadHmac<L, M>] {
+        self.raw.as_ref()
     }
 }
 
-impl<'a, L, M, N, I> BitXorAssign<&'a mut I> for Path<L, M, N>
+impl<L, M, N> AsMut<[PayloadHmac<L, M>]> for Path<L, M, N>
 where
     L: ArrayLength<u8>,
     M: ArrayLength<u8>,
     N: ArrayLength<PayloadHmac<L, M>>,
-    I: KeyStream,
 {
-    fn bitxor_assign(&mut self, rhs: &'a mut I) {
-        self.as_mut().iter_mut().for_each(|x| *x ^= rhs);
+    fn as_mut(&mut self) -> &mut [PayloadHmac<L, M>] {
+        self.raw.as_mut()
     }
 }
 
 mod implementations {
     use super::{PayloadHmac, Path};
     use generic_array::ArrayLength;
-    use std::fmt;
+    use core::fmt;
 
     impl<L, M> fmt::Debug for PayloadHmac<L, M>
     where
@@ -0,0 +1,293 @@
+use super::path::PayloadHmac;
+use super::sphinx::{Sphinx, SharedSecret};
+use super::packet::{AuthenticatedMessage, LocalData, Processed, ProcessError};
+
+use generic_array::ArrayLength;
+use rac::{LineValid, Curve};
+use digest::{Input, FixedOutput};
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, HashSet};
+#[cfg(feature = "std")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::path::{Path as FsPath, PathBuf};
+
+pub trait ReplayCache {
+    type Error;
+
+    fn contains(&self, tag: &[u8]) -> Result<bool, Self::Error>;
+
+    fn insert(&mut self, epoch: u64, tag: &[u8]) -> Result<(), Self::Error>;
+
+    // forget all tags inserted in epochs strictly before `epoch`
+    fn expire(&mut self, epoch: u64) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReplayError<E> {
+    Process(ProcessError),
+    Cache(E),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CacheFull;
+
+#[cfg(feature = "std")]
+pub struct MemoryReplayCache {
+    capacity: usize,
+    length: usize,
+    epochs: BTreeMap<u64, HashSet<Vec<u8>>>,
+}
+
+#[cfg(feature = "std")]
+impl MemoryReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        MemoryReplayCache {
+            capacity: capacity,
+            length: 0,
+            epochs: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.epochs
+            .iter()
+            .flat_map(|(&epoch, tags)| tags.iter().map(move |tag| (epoch, tag.as_ref())))
+    }
+}
+
+#[cfg(feature = "std")]
+impl ReplayCache for MemoryReplayCache {
+    type Error = CacheFull;
+
+    fn contains(&self, tag: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.epochs.values().any(|tags| tags.contains(tag)))
+    }
+
+    fn insert(&mut self, epoch: u64, tag: &[u8]) -> Result<(), Self::Error> {
+        if self.contains(tag)? {
+            return Ok(());
+        }
+
+        // make room by dropping whole epochs which are older than the new tag
+        while self.length >= self.capacity {
+            let oldest = match self.epochs.keys().next() {
+                Some(&oldest) if oldest < epoch => oldest,
+                _ => return Err(CacheFull),
+            };
+            let tags = self.epochs.remove(&oldest).unwrap_or_default();
+            self.length -= tags.len();
+        }
+
+        self.epochs.entry(epoch).or_default().insert(tag.to_vec());
+        self.length += 1;
+        Ok(())
+    }
+
+    fn expire(&mut self, epoch: u64) -> Result<(), Self::Error> {
+        self.epochs = self.epochs.split_off(&epoch);
+        self.length = self.epochs.values().map(HashSet::len).sum();
+        Ok(())
+    }
+}
+
+// the file is a sequence of records: epoch as 8 bytes big endian,
+// length of the tag as a single byte, the tag itself
+#[cfg(feature = "std")]
+pub struct FileReplayCache {
+    path: PathBuf,
+    file: File,
+    memory: MemoryReplayCache,
+}
+
+#[cfg(feature = "std")]
+impl FileReplayCache {
+    pub fn open<P>(path: P, capacity: usize) -> io::Result<Self>
+    where
+        P: AsRef<FsPath>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let mut memory = MemoryReplayCache::new(capacity);
+
+        if path.exists() {
+            let mut buffer = Vec::new();
+            File::open(&path)?.read_to_end(&mut buffer)?;
+            let mut rest = buffer.as_slice();
+            while !rest.is_empty() {
+                if rest.len() < 9 || rest.len() < 9 + (rest[8] as usize) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated replay cache record",
+                    ));
+                }
+                let mut epoch = [0; 8];
+                epoch.copy_from_slice(&rest[0..8]);
+                let end = 9 + (rest[8] as usize);
+                memory
+                    .insert(u64::from_be_bytes(epoch), &rest[9..end])
+                    .map_err(Self::full)?;
+                rest = &rest[end..];
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileReplayCache {
+            path: path,
+            file: file,
+            memory: memory,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    fn full(_: CacheFull) -> io::Error {
+        io::Error::other("replay cache is full")
+    }
+
+    fn record(epoch: u64, tag: &[u8]) -> io::Result<Vec<u8>> {
+        if tag.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "replay tag is too long",
+            ));
+        }
+        let mut record = Vec::with_capacity(9 + tag.len());
+        record.extend_from_slice(&epoch.to_be_bytes());
+        record.push(tag.len() as u8);
+        record.extend_from_slice(tag);
+        Ok(record)
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        let mut buffer = Vec::new();
+        for (epoch, tag) in self.memory.entries() {
+            buffer.extend_from_slice(Self::record(epoch, tag)?.as_ref());
+        }
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(buffer.as_ref())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ReplayCache for FileReplayCache {
+    type Error = io::Error;
+
+    fn contains(&self, tag: &[u8]) -> Result<bool, Self::Error> {
+        self.memory.contains(tag).map_err(Self::full)
+    }
+
+    fn insert(&mut self, epoch: u64, tag: &[u8]) -> Result<(), Self::Error> {
+        if self.contains(tag)? {
+            return Ok(());
+        }
+
+        let record = Self::record(epoch, tag)?;
+        let before = self.memory.len();
+        self.memory.insert(epoch, tag).map_err(Self::full)?;
+        if self.memory.len() <= before {
+            // some epochs were evicted to make room, the file must be compacted
+            self.compact()
+        } else {
+            self.file.write_all(record.as_ref())?;
+            self.file.sync_data()
+        }
+    }
+
+    fn expire(&mut self, epoch: u64) -> Result<(), Self::Error> {
+        self.memory.expire(epoch).map_err(Self::full)?;
+        self.compact()
+    }
+}
+
+impl<B, L, N, P> AuthenticatedMessage<B, L, N, P>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+    P: AsMut<[u8]>,
+{
+    // the tag is the digest of the shared secret, it is checked before the packet is touched
+    // and remembered only if the packet is authentic
+    pub fn process_with_replay<D, T, C>(
+        self,
+        associated_data: T,
+        local: &LocalData<B::AsymmetricKey>,
+        epoch: u64,
+        cache: &mut C,
+    ) -> Result<Processed<B, L, N, P>, ReplayError<C::Error>>
+    where
+        D: Default
+            + Input
+            + FixedOutput<OutputSize = <<B::AsymmetricKey as Curve>::Scalar as LineValid>::Length>,
+        T: AsRef<[u8]>,
+        C: ReplayCache,
+    {
+        let tag = local.digest::<D>().shared_secret.clone();
+        if cache.contains(tag.as_ref()).map_err(ReplayError::Cache)? {
+            return Err(ReplayError::Process(ProcessError::Replay));
+        }
+
+        let processed = self
+            .process(associated_data, local)
+            .map_err(ReplayError::Process)?;
+        cache
+            .insert(epoch, tag.as_ref())
+            .map_err(ReplayError::Cache)?;
+        Ok(processed)
+    }
+}
+
+mod implementations {
+    use super::{ReplayError, CacheFull};
+    use core::fmt;
+
+    impl<E> fmt::Display for ReplayError<E>
+    where
+        E: fmt::Display,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ReplayError::Process(ref e) => write!(f, "{}", e),
+                ReplayError::Cache(ref e) => write!(f, "replay cache error: {}", e),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<E> std::error::Error for ReplayError<E> where E: fmt::Debug + fmt::Display {}
+
+    impl fmt::Display for CacheFull {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "replay cache is full")
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for CacheFull {}
+}
@@ -0,0 +1,274 @@
+use super::path::{PayloadHmac, Path};
+use super::sphinx::{Sphinx, SharedSecret, PayloadCipher};
+use super::packet::{AuthenticatedMessage, GlobalData, Processed, ProcessError, prepend_tag, strip_tag};
+
+use generic_array::{GenericArray, ArrayLength};
+use rac::{LineValid, Curve};
+
+// the ephemeral key the first hop gets together with the sealed packet
+pub type SealedReply<B, L, N, P> = (
+    <B as Sphinx>::AsymmetricKey,
+    AuthenticatedMessage<B, L, N, P>,
+);
+
+// single use reply block, the header of a packet prepared by the recipient,
+// the last hop of its path is the recipient itself
+pub struct ReplyBlock<B, L, N>
+where
+    B: Sphinx,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+{
+    public_key: B::AsymmetricKey,
+    first_hop: GenericArray<u8, L>,
+    routing_info: Path<L, B::MacLength, N>,
+    hmac: GenericArray<u8, B::MacLength>,
+    key: SharedSecret<B::AsymmetricKey>,
+}
+
+// kept by the creator of the reply block, knows every key the reply is encrypted with
+pub struct ReplyDecryptor<B, N>
+where
+    B: Sphinx,
+    N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+{
+    shared_secrets: GenericArray<SharedSecret<B::AsymmetricKey>, N>,
+    length: usize,
+    key: SharedSecret<B::AsymmetricKey>,
+}
+
+impl<B, N> Drop for ReplyDecryptor<B, N>
+where
+    B: Sphinx,
+    N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+{
+    fn drop(&mut self) {
+        use super::sphinx::wipe;
+
+        self.shared_secrets
+            .iter_mut()
+            .for_each(|shared_secret| wipe(shared_secret.as_mut()));
+        wipe(self.key.as_mut());
+    }
+}
+
+impl<B, L, N> ReplyBlock<B, L, N>
+where
+    B: Sphinx,
+    B::AsymmetricKey: Clone,
+    <B::AsymmetricKey as Curve>::Scalar: Clone,
+    L: ArrayLength<u8>,
+    N: ArrayLength<PayloadHmac<L, B::MacLength>> + ArrayLength<SharedSecret<B::AsymmetricKey>>,
+{
+    pub fn new<H, I, T>(
+        session_key: &<B::AsymmetricKey as Curve>::Scalar,
+        path: H,
+        first_hop: GenericArray<u8, L>,
+        payloads: I,
+        associated_data: T,
+    ) -> Result<(Self, ReplyDecryptor<B, N>), ProcessError>
+    where
+        H: Iterator<Item = B::AsymmetricKey>,
+        I: Iterator<Item = GenericArray<u8, L>> + DoubleEndedIterator + ExactSizeIterator,
+        T: AsRef<[u8]>,
+    {
+        let length = payloads.len();
+        let (data, public_key) = GlobalData::new::<_, B>(session_key, path)?;
+        let shared_secrets = data.shared_secrets.clone();
+        // keyed by the session secret, so only the creator can derive it
+        let key = B::blinding(&public_key, &session_key.clone_line());
+
+        let (routing_info, hmac) = AuthenticatedMessage::<B, L, N, [u8; 0]>::header(
+            &shared_secrets,
+            associated_data,
+            payloads,
+        )?;
+
+        let reply = ReplyBlock {
+            public_key: public_key,
+            first_hop: first_hop,
+            routing_info: routing_info,
+            hmac: hmac,
+            key: key.clone(),
+        };
+        let decryptor = ReplyDecryptor {
+            shared_secrets: shared_secrets,
+            length: length,
+            key: key,
+        };
+        Ok((reply, decryptor))
+    }
+
+    pub fn first_hop(&self) -> &GenericArray<u8, L> {
+        &self.first_hop
+    }
+
+    pub fn seal<P>(self, message: P) -> Result<SealedReply<B, L, N, P>, ProcessError>
+    where
+        P: AsMut<[u8]>,
+    {
+        let mut message = message;
+        prepend_tag::<B>(message.as_mut())?;
+        B::PayloadCipher::encrypt(&self.key, message.as_mut())?;
+
+        let packet = AuthenticatedMessage {
+            routing_info: self.routing_info,
+            hmac: self.hmac,
+            message: message,
+        };
+        Ok((self.public_key, packet))
+    }
+}
+
+impl<B, N> ReplyDecryptor<B, N>
+where
+    B: Sphinx,
+    N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+{
+    // the packet is the one delivered to the creator, as the last hop of the path
+    pub fn open<L, P, T>(
+        &self,
+        packet: AuthenticatedMessage<B, L, N, P>,
+        associated_data: T,
+    ) -> Result<(GenericArray<u8, L>, P), ProcessError>
+    where
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+        P: AsMut<[u8]>,
+        T: AsRef<[u8]>,
+    {
+        let last = self
+            .length
+            .checked_sub(1)
+            .ok_or(ProcessError::MacMismatch)?;
+        match packet.peel(associated_data, &self.shared_secrets[last])? {
+            // the header is built by the creator, it ends at the last hop
+            Processed::Forward { .. } => Err(ProcessError::MacMismatch),
+            Processed::Exit {
+                data: data,
+                message: message,
+            } => {
+                // the hops have decrypted the payload, undo it in reverse order
+                let mut message = message;
+                for i in (0..last).rev() {
+                    B::PayloadCipher::encrypt(&self.shared_secrets[i], message.as_mut())?;
+                }
+                B::PayloadCipher::decrypt(&self.key, message.as_mut())?;
+                strip_tag::<B>(message.as_mut())?;
+                Ok((data, message))
+            },
+        }
+    }
+}
+
+#[cfg(feature = "serde-support")]
+mod serde_m {
+    use super::{ReplyBlock, Path, PayloadHmac, Sphinx, SharedSecret};
+
+    use generic_array::{GenericArray, ArrayLength};
+    use rac::{LineValid, Curve};
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use core::marker::PhantomData;
+    use core::fmt;
+
+    type CompressedLength<A> = <<A as Curve>::CompressedCurve as LineValid>::Length;
+
+    impl<B, L, N> Serialize for ReplyBlock<B, L, N>
+    where
+        B: Sphinx,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            use serde::ser::SerializeTuple;
+
+            let mut tuple = serializer.serialize_tuple(5)?;
+            tuple.serialize_element(&self.public_key.compress().clone_line())?;
+            tuple.serialize_element(&self.first_hop)?;
+            tuple.serialize_element(&self.routing_info)?;
+            tuple.serialize_element(&self.hmac)?;
+            tuple.serialize_element(&self.key)?;
+            tuple.end()
+        }
+    }
+
+    impl<'de, B, L, N> Deserialize<'de> for ReplyBlock<B, L, N>
+    where
+        B: Sphinx,
+        L: ArrayLength<u8>,
+        N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+    {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            use serde::de::{Visitor, SeqAccess, Error};
+
+            struct V<B, L, N>
+            where
+                B: Sphinx,
+                L: ArrayLength<u8>,
+                N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+            {
+                phantom_data: PhantomData<(B, L, N)>,
+            }
+
+            impl<'de, B, L, N> Visitor<'de> for V<B, L, N>
+            where
+                B: Sphinx,
+                L: ArrayLength<u8>,
+                N: ArrayLength<PayloadHmac<L, B::MacLength>>,
+            {
+                type Value = ReplyBlock<B, L, N>;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, "bytes")
+                }
+
+                fn visit_seq<S>(self, mut sequence: S) -> Result<Self::Value, S::Error>
+                where
+                    S: SeqAccess<'de>,
+                {
+                    let c: GenericArray<u8, CompressedLength<B::AsymmetricKey>> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let f: GenericArray<u8, L> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let p: Path<L, B::MacLength, N> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let m: GenericArray<u8, B::MacLength> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let k: SharedSecret<B::AsymmetricKey> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+
+                    let c = LineValid::try_clone_array(&c)
+                        .map_err(|_| Error::custom("invalid public key"))?;
+                    let public_key = <B::AsymmetricKey as Curve>::decompress(&c)
+                        .map_err(|_| Error::custom("invalid public key"))?;
+
+                    Ok(ReplyBlock {
+                        public_key: public_key,
+                        first_hop: f,
+                        routing_info: p,
+                        hmac: m,
+                        key: k,
+                    })
+                }
+            }
+
+            deserializer.deserialize_tuple(
+                5,
+                V {
+                    phantom_data: PhantomData::<(B, L, N)>,
+                },
+            )
+        }
+    }
+}
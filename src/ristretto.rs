@@ -0,0 +1,109 @@
+use generic_array::{GenericArray, typenum::U32};
+use rac::{LineValid, Scalar, Curve};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar as DalekScalar,
+};
+
+// a nonzero scalar modulo the prime group order, any 32 bytes are reduced,
+// so a hash output is a valid blinding factor
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ristretto255Scalar(DalekScalar);
+
+impl Drop for Ristretto255Scalar {
+    fn drop(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+impl LineValid for Ristretto255Scalar {
+    type Length = U32;
+
+    fn try_clone_array(a: &GenericArray<u8, Self::Length>) -> Result<Self, ()> {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(a.as_ref());
+        let scalar = DalekScalar::from_bytes_mod_order(bytes);
+        if scalar == DalekScalar::zero() {
+            Err(())
+        } else {
+            Ok(Ristretto255Scalar(scalar))
+        }
+    }
+
+    fn clone_line(&self) -> GenericArray<u8, Self::Length> {
+        GenericArray::clone_from_slice(self.0.as_bytes())
+    }
+}
+
+impl Scalar for Ristretto255Scalar {
+    fn add_ff(&self, rhs: &Self) -> Result<Self, ()> {
+        let sum = self.0 + rhs.0;
+        if sum == DalekScalar::zero() {
+            Err(())
+        } else {
+            Ok(Ristretto255Scalar(sum))
+        }
+    }
+
+    fn mul_ff(&self, rhs: &Self) -> Result<Self, ()> {
+        Ok(Ristretto255Scalar(self.0 * rhs.0))
+    }
+
+    fn inv_ff(&self) -> Result<Self, ()> {
+        Ok(Ristretto255Scalar(self.0.invert()))
+    }
+}
+
+// an element of the prime order group, the encoding is canonical,
+// so there is exactly one byte string for each element and the cofactor never shows up
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ristretto255Point(RistrettoPoint);
+
+impl Ristretto255Point {
+    pub fn from_secret(secret_key: &Ristretto255Scalar) -> Self {
+        Ristretto255Point::base().exp_ec(secret_key)
+    }
+}
+
+impl LineValid for Ristretto255Point {
+    type Length = U32;
+
+    // rejects the non canonical encodings
+    fn try_clone_array(a: &GenericArray<u8, Self::Length>) -> Result<Self, ()> {
+        CompressedRistretto::from_slice(a.as_ref())
+            .decompress()
+            .map(Ristretto255Point)
+            .ok_or(())
+    }
+
+    fn clone_line(&self) -> GenericArray<u8, Self::Length> {
+        GenericArray::clone_from_slice(self.0.compress().as_bytes())
+    }
+}
+
+impl Curve for Ristretto255Point {
+    type Scalar = Ristretto255Scalar;
+    type CompressedCurve = Self;
+
+    fn base() -> Self {
+        Ristretto255Point(RISTRETTO_BASEPOINT_POINT)
+    }
+
+    fn mul_ec(&self, rhs: &Self) -> Self {
+        Ristretto255Point(self.0 + rhs.0)
+    }
+
+    fn exp_ec(&self, rhs: &Self::Scalar) -> Self {
+        Ristretto255Point(self.0 * rhs.0)
+    }
+
+    fn decompress(packed: &Self::CompressedCurve) -> Result<Self, ()> {
+        Ok(*packed)
+    }
+
+    fn compress(&self) -> Self::CompressedCurve {
+        *self
+    }
+}
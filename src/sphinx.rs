@@ -3,6 +3,9 @@ use keystream::{KeyStream, SeekableKeyStream};
 use rac::{LineValid, Curve};
 use crypto_mac::Mac;
 use digest::{Input, FixedOutput};
+use core::marker::PhantomData;
+
+use super::packet::ProcessError;
 
 pub trait PseudoRandomStream<T>
 where
@@ -13,12 +16,192 @@ where
 
 pub type SharedSecret<A> = GenericArray<u8, <<A as Curve>::Scalar as LineValid>::Length>;
 
+// constant time, so the timing does not tell how many bytes of a mac matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    use subtle::ConstantTimeEq;
+
+    a.ct_eq(b).into()
+}
+
+// the zero hmac marks the exit, it is checked in constant time as well
+pub(crate) fn constant_time_is_zero(a: &[u8]) -> bool {
+    use subtle::ConstantTimeEq;
+
+    let mut acc = 0u8;
+    for &x in a {
+        acc |= x;
+    }
+    acc.ct_eq(&0).into()
+}
+
+// overwrites key material with zeros, a no op unless the `zeroize` feature is on
+pub(crate) fn wipe(bytes: &mut [u8]) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(bytes);
+    #[cfg(not(feature = "zeroize"))]
+    let _ = bytes;
+}
+
+// a stream or a mac keyed by a secret, on drop its state is replaced by the one
+// of the zero key, a no op unless the `zeroize` feature is on
+//
+// the ciphers do not implement `Zeroize` and their state can not be reached as bytes
+// without `unsafe`, so unlike `wipe` this is not a volatile write: the blank state is
+// stored over the old one in place, before the old one is dropped, and a compiler fence
+// follows, which keeps the store in practice but is not guaranteed against the optimizer;
+// copies the cipher made of its own state (by moves or on the heap) are not reached
+pub struct Wiped<T> {
+    inner: T,
+    blank: fn() -> T,
+}
+
+impl<T> Wiped<T> {
+    pub(crate) fn new(inner: T, blank: fn() -> T) -> Self {
+        Wiped {
+            inner: inner,
+            blank: blank,
+        }
+    }
+
+    // not inlined, so the store is not removed as a dead one
+    #[inline(never)]
+    pub(crate) fn wipe(&mut self) {
+        #[cfg(feature = "zeroize")]
+        {
+            use core::sync::atomic::{compiler_fence, Ordering};
+
+            let old = core::mem::replace(&mut self.inner, (self.blank)());
+            compiler_fence(Ordering::SeqCst);
+            drop(old);
+        }
+        #[cfg(not(feature = "zeroize"))]
+        let _ = self.blank;
+    }
+}
+
+impl<T> Drop for Wiped<T> {
+    fn drop(&mut self) {
+        self.wipe()
+    }
+}
+
+impl<T> KeyStream for Wiped<T>
+where
+    T: KeyStream,
+{
+    fn xor_read(&mut self, dest: &mut [u8]) -> Result<(), keystream::Error> {
+        self.inner.xor_read(dest)
+    }
+}
+
+impl<T> SeekableKeyStream for Wiped<T>
+where
+    T: SeekableKeyStream,
+{
+    fn seek_to(&mut self, byte_offset: u64) -> Result<(), keystream::Error> {
+        self.inner.seek_to(byte_offset)
+    }
+}
+
+pub(crate) fn blank_mac<C>() -> C
+where
+    C: Mac,
+{
+    C::new(&GenericArray::default())
+}
+
+pub(crate) fn blank_stream<T, S>() -> S
+where
+    T: ArrayLength<u8>,
+    S: PseudoRandomStream<T>,
+{
+    S::seed(GenericArray::default())
+}
+
+// the mac of the data under the key, the collector is wiped
+pub(crate) fn mac<C>(key: &[u8], data: &[u8]) -> GenericArray<u8, C::OutputSize>
+where
+    C: Mac,
+{
+    let mut collector = Wiped::new(C::new_varkey(key).unwrap(), blank_mac::<C>);
+    collector.inner.input(data);
+    collector.inner.result_reset().code()
+}
+
+fn keyed_mac<C>(label: &[u8], shared: &[u8]) -> Wiped<C>
+where
+    C: Mac,
+{
+    let mut key = mac::<C>(label, shared);
+    let collector = Wiped::new(C::new_varkey(&key).unwrap(), blank_mac::<C>);
+    wipe(key.as_mut());
+    collector
+}
+
+fn keyed_stream<C, S>(label: &[u8], shared: &[u8]) -> Wiped<S>
+where
+    C: Mac,
+    S: PseudoRandomStream<C::OutputSize>,
+{
+    let mut key = mac::<C>(label, shared);
+    let stream = Wiped::new(S::seed(key.clone()), blank_stream::<C::OutputSize, S>);
+    wipe(key.as_mut());
+    stream
+}
+
+pub trait PayloadCipher<A>
+where
+    A: Curve,
+{
+    // the length of the zero tag put in front of the message, the exit checks and removes it,
+    // so the message must leave that many bytes free at its end
+    fn tag_length() -> usize;
+
+    fn encrypt(shared: &SharedSecret<A>, payload: &mut [u8]) -> Result<(), ProcessError>;
+
+    fn decrypt(shared: &SharedSecret<A>, payload: &mut [u8]) -> Result<(), ProcessError>;
+}
+
+// xor with the `pi` stream, malleable, but compatible with BOLT #4
+pub struct XorCipher<B>
+where
+    B: Sphinx,
+{
+    phantom_data: PhantomData<B>,
+}
+
+impl<B> PayloadCipher<B::AsymmetricKey> for XorCipher<B>
+where
+    B: Sphinx,
+{
+    fn tag_length() -> usize {
+        0
+    }
+
+    fn encrypt(
+        shared: &SharedSecret<B::AsymmetricKey>,
+        payload: &mut [u8],
+    ) -> Result<(), ProcessError> {
+        B::pi(shared)
+            .xor_read(payload)
+            .map_err(ProcessError::Stream)
+    }
+
+    fn decrypt(
+        shared: &SharedSecret<B::AsymmetricKey>,
+        payload: &mut [u8],
+    ) -> Result<(), ProcessError> {
+        Self::encrypt(shared, payload)
+    }
+}
+
 pub trait Sphinx {
     type KeyLength: ArrayLength<u8>;
     type MacLength: ArrayLength<u8>;
     type AsymmetricKey: Curve;
     type Stream: KeyStream + SeekableKeyStream;
     type Collector;
+    type PayloadCipher: PayloadCipher<Self::AsymmetricKey>;
 
     fn mu(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector;
 
@@ -34,6 +217,20 @@ pub trait Sphinx {
 
     fn tau(public_key: Self::AsymmetricKey) -> SharedSecret<Self::AsymmetricKey>;
 
+    // fills the unused routing info, keyed by the session key rather than by a shared secret,
+    // by default it is `rho` under the session key, the suites of this crate have a label for it
+    fn pad(session_key: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        Self::rho(session_key)
+    }
+
+    // the next hmac the exit finds in its slot of a padded packet,
+    // by default it is the mac of `exit` under `mu`
+    fn exit_marker(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength> {
+        Self::output(Self::chain(Self::mu(shared), b"exit"))
+    }
+
     fn blinding(
         public_key: &Self::AsymmetricKey,
         shared: &SharedSecret<Self::AsymmetricKey>,
@@ -50,14 +247,12 @@ where
     type KeyLength = C::KeySize;
     type MacLength = C::OutputSize;
     type AsymmetricKey = A;
-    type Stream = S;
-    type Collector = C;
+    type Stream = Wiped<S>;
+    type Collector = Wiped<C>;
+    type PayloadCipher = XorCipher<Self>;
 
     fn mu(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector {
-        let mut collector = C::new_varkey(b"mu").unwrap();
-        collector.input(shared);
-        let key = collector.result().code();
-        C::new_varkey(&key).unwrap()
+        keyed_mac(b"mu", shared)
     }
 
     fn chain<T>(collector: Self::Collector, data: T) -> Self::Collector
@@ -65,26 +260,23 @@ where
         T: AsRef<[u8]>,
     {
         let mut collector = collector;
-        Mac::input(&mut collector, data.as_ref());
+        Mac::input(&mut collector.inner, data.as_ref());
         collector
     }
 
     fn output(collector: Self::Collector) -> GenericArray<u8, Self::MacLength> {
-        Mac::result(collector).code()
+        let mut collector = collector;
+        Mac::result_reset(&mut collector.inner).code()
     }
 
     fn rho(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
-        let mut collector = C::new_varkey(b"rho").unwrap();
-        collector.input(shared);
-        let key = collector.result().code();
-        S::seed(key)
+        keyed_stream::<C, S>(b"rho", shared)
     }
 
+    // the label was `um` before, which BOLT #4 gives to the hmac of the failure message,
+    // the two keys are separate now, a payload encrypted by an earlier version does not decrypt
     fn pi(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
-        let mut collector = C::new_varkey(b"um").unwrap();
-        collector.input(shared);
-        let key = collector.result().code();
-        S::seed(key)
+        keyed_stream::<C, S>(b"pi", shared)
     }
 
     fn tau(public_key: Self::AsymmetricKey) -> SharedSecret<Self::AsymmetricKey> {
@@ -93,6 +285,16 @@ where
             .fixed_result()
     }
 
+    fn pad(session_key: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        keyed_stream::<C, S>(b"pad", session_key)
+    }
+
+    fn exit_marker(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength> {
+        mac::<C>(b"exit", shared)
+    }
+
     fn blinding(
         public_key: &Self::AsymmetricKey,
         shared: &SharedSecret<Self::AsymmetricKey>,
@@ -103,3 +305,153 @@ where
             .fixed_result()
     }
 }
+
+// same as the above, but with the payload cipher chosen explicitly
+impl<A, C, D, S, W> Sphinx for (A, C, D, S, W)
+where
+    A: Curve,
+    C: Mac,
+    D: Default + Input + FixedOutput<OutputSize = <<A as Curve>::Scalar as LineValid>::Length>,
+    S: PseudoRandomStream<C::OutputSize> + SeekableKeyStream,
+    W: PayloadCipher<A>,
+{
+    type KeyLength = C::KeySize;
+    type MacLength = C::OutputSize;
+    type AsymmetricKey = A;
+    type Stream = Wiped<S>;
+    type Collector = Wiped<C>;
+    type PayloadCipher = W;
+
+    fn mu(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector {
+        <(A, C, D, S) as Sphinx>::mu(shared)
+    }
+
+    fn chain<T>(collector: Self::Collector, data: T) -> Self::Collector
+    where
+        T: AsRef<[u8]>,
+    {
+        <(A, C, D, S) as Sphinx>::chain(collector, data)
+    }
+
+    fn output(collector: Self::Collector) -> GenericArray<u8, Self::MacLength> {
+        <(A, C, D, S) as Sphinx>::output(collector)
+    }
+
+    fn rho(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        <(A, C, D, S) as Sphinx>::rho(shared)
+    }
+
+    fn pi(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        <(A, C, D, S) as Sphinx>::pi(shared)
+    }
+
+    fn tau(public_key: Self::AsymmetricKey) -> SharedSecret<Self::AsymmetricKey> {
+        <(A, C, D, S) as Sphinx>::tau(public_key)
+    }
+
+    fn pad(session_key: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        <(A, C, D, S) as Sphinx>::pad(session_key)
+    }
+
+    fn exit_marker(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength> {
+        <(A, C, D, S) as Sphinx>::exit_marker(shared)
+    }
+
+    fn blinding(
+        public_key: &Self::AsymmetricKey,
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> SharedSecret<Self::AsymmetricKey> {
+        <(A, C, D, S) as Sphinx>::blinding(public_key, shared)
+    }
+}
+
+// keys of the failure message of BOLT #4, authentication and obfuscation,
+// only the failure packets need them, so they are not part of `Sphinx`
+pub trait ErrorKeys: Sphinx {
+    fn um(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector;
+
+    fn ammag(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream;
+
+    // obfuscation of the attribution data of the failure message
+    fn ammag_ext(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream;
+}
+
+// the key derivation of route blinding
+pub trait BlindingKeys: Sphinx {
+    // tweak of a node id in a blinded route
+    fn blinded_node_id(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength>;
+}
+
+impl<A, C, D, S> ErrorKeys for (A, C, D, S)
+where
+    A: Curve,
+    C: Mac,
+    D: Default + Input + FixedOutput<OutputSize = <<A as Curve>::Scalar as LineValid>::Length>,
+    S: PseudoRandomStream<C::OutputSize> + SeekableKeyStream,
+{
+    fn um(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector {
+        keyed_mac(b"um", shared)
+    }
+
+    fn ammag(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        keyed_stream::<C, S>(b"ammag", shared)
+    }
+
+    fn ammag_ext(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        keyed_stream::<C, S>(b"ammagext", shared)
+    }
+}
+
+impl<A, C, D, S, W> ErrorKeys for (A, C, D, S, W)
+where
+    A: Curve,
+    C: Mac,
+    D: Default + Input + FixedOutput<OutputSize = <<A as Curve>::Scalar as LineValid>::Length>,
+    S: PseudoRandomStream<C::OutputSize> + SeekableKeyStream,
+    W: PayloadCipher<A>,
+{
+    fn um(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Collector {
+        <(A, C, D, S) as ErrorKeys>::um(shared)
+    }
+
+    fn ammag(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        <(A, C, D, S) as ErrorKeys>::ammag(shared)
+    }
+
+    fn ammag_ext(shared: &SharedSecret<Self::AsymmetricKey>) -> Self::Stream {
+        <(A, C, D, S) as ErrorKeys>::ammag_ext(shared)
+    }
+}
+
+impl<A, C, D, S> BlindingKeys for (A, C, D, S)
+where
+    A: Curve,
+    C: Mac,
+    D: Default + Input + FixedOutput<OutputSize = <<A as Curve>::Scalar as LineValid>::Length>,
+    S: PseudoRandomStream<C::OutputSize> + SeekableKeyStream,
+{
+    fn blinded_node_id(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength> {
+        mac::<C>(b"blinded_node_id", shared)
+    }
+}
+
+impl<A, C, D, S, W> BlindingKeys for (A, C, D, S, W)
+where
+    A: Curve,
+    C: Mac,
+    D: Default + Input + FixedOutput<OutputSize = <<A as Curve>::Scalar as LineValid>::Length>,
+    S: PseudoRandomStream<C::OutputSize> + SeekableKeyStream,
+    W: PayloadCipher<A>,
+{
+    fn blinded_node_id(
+        shared: &SharedSecret<Self::AsymmetricKey>,
+    ) -> GenericArray<u8, Self::MacLength> {
+        <(A, C, D, S) as BlindingKeys>::blinded_node_id(shared)
+    }
+}
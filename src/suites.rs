@@ -0,0 +1,136 @@
+// named instantiations of the sphinx trait, two nodes agree on the parameters
+// by the name of the suite, each one is behind its own feature
+
+#[cfg(any(feature = "suite-secp256k1", feature = "suite-x25519-blake2b"))]
+mod chacha20 {
+    use super::super::sphinx::PseudoRandomStream;
+
+    use generic_array::{
+        GenericArray,
+        typenum::{U32, U64},
+    };
+    use chacha::ChaCha;
+
+    // chacha20 with zero nonce, as BOLT #4 does
+    impl PseudoRandomStream<U32> for ChaCha {
+        fn seed(v: GenericArray<u8, U32>) -> Self {
+            let mut array = [0; 32];
+            array.copy_from_slice(v.as_ref());
+            ChaCha::new_chacha20(&array, &[0u8; 8])
+        }
+    }
+
+    // the key is the first half of the seed, the nonce is zero
+    impl PseudoRandomStream<U64> for ChaCha {
+        fn seed(v: GenericArray<u8, U64>) -> Self {
+            let mut array = [0; 32];
+            array.copy_from_slice(&v[..32]);
+            ChaCha::new_chacha20(&array, &[0u8; 8])
+        }
+    }
+}
+
+#[cfg(feature = "suite-secp256k1")]
+mod secp256k1_sha256_chacha20 {
+    use secp256k1::PublicKey;
+    use hmac::Hmac;
+    use sha2::Sha256;
+    use chacha::ChaCha;
+
+    // compatible with BOLT #4
+    pub type Secp256k1Sha256ChaCha20 = (PublicKey, Hmac<Sha256>, Sha256, ChaCha);
+}
+
+#[cfg(feature = "suite-secp256k1")]
+pub use self::secp256k1_sha256_chacha20::Secp256k1Sha256ChaCha20;
+
+#[cfg(feature = "suite-x25519-blake2b")]
+mod x25519_blake2b_chacha20 {
+    use super::super::x25519::X25519Point;
+
+    use generic_array::{GenericArray, typenum::U32};
+    use digest::{Input, FixedOutput, VariableOutput};
+    use blake2::{Blake2b, VarBlake2b};
+    use chacha::ChaCha;
+
+    // the shared secret must be as long as a scalar, so the digest is truncated to 32 bytes,
+    // the mac is keyed blake2b with the full 64 byte output
+    pub type X25519Blake2bChaCha20 = (X25519Point, Blake2b, Blake2b256, ChaCha);
+
+    #[derive(Clone)]
+    pub struct Blake2b256(VarBlake2b);
+
+    impl Default for Blake2b256 {
+        fn default() -> Self {
+            Blake2b256(VarBlake2b::new(32).unwrap())
+        }
+    }
+
+    impl Input for Blake2b256 {
+        fn input<B: AsRef<[u8]>>(&mut self, data: B) {
+            self.0.input(data)
+        }
+    }
+
+    impl FixedOutput for Blake2b256 {
+        type OutputSize = U32;
+
+        fn fixed_result(self) -> GenericArray<u8, Self::OutputSize> {
+            let mut output = GenericArray::default();
+            self.0
+                .variable_result(|result| output.copy_from_slice(result));
+            output
+        }
+    }
+}
+
+#[cfg(feature = "suite-x25519-blake2b")]
+pub use self::x25519_blake2b_chacha20::{X25519Blake2bChaCha20, Blake2b256};
+
+#[cfg(feature = "suite-x25519-aes")]
+mod x25519_sha512256_aes256ctr {
+    use super::super::x25519::X25519Point;
+    use super::super::sphinx::PseudoRandomStream;
+
+    use generic_array::{GenericArray, typenum::U32};
+    use keystream::{KeyStream, SeekableKeyStream, Error};
+    use hmac::Hmac;
+    use sha2::Sha512Trunc256;
+    use aes_ctr::{
+        Aes256Ctr,
+        cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek},
+    };
+
+    pub type X25519Sha512256Aes256Ctr = (
+        X25519Point,
+        Hmac<Sha512Trunc256>,
+        Sha512Trunc256,
+        Aes256CtrStream,
+    );
+
+    // aes-256 in counter mode with zero nonce
+    pub struct Aes256CtrStream(Aes256Ctr);
+
+    impl PseudoRandomStream<U32> for Aes256CtrStream {
+        fn seed(v: GenericArray<u8, U32>) -> Self {
+            Aes256CtrStream(Aes256Ctr::new_var(v.as_ref(), &[0; 16]).unwrap())
+        }
+    }
+
+    impl KeyStream for Aes256CtrStream {
+        fn xor_read(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+            self.0
+                .try_apply_keystream(dest)
+                .map_err(|_| Error::EndReached)
+        }
+    }
+
+    impl SeekableKeyStream for Aes256CtrStream {
+        fn seek_to(&mut self, byte_offset: u64) -> Result<(), Error> {
+            self.0.try_seek(byte_offset).map_err(|_| Error::EndReached)
+        }
+    }
+}
+
+#[cfg(feature = "suite-x25519-aes")]
+pub use self::x25519_sha512256_aes256ctr::{X25519Sha512256Aes256Ctr, Aes256CtrStream};
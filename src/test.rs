@@ -3,21 +3,26 @@ use secp256k1::{PublicKey, SecretKey};
 use super::Processed;
 
 mod packet {
-    use super::super::{PseudoRandomStream, AuthenticatedMessage};
+    use super::super::{PseudoRandomStream, AuthenticatedMessage, Lioness};
     use sha2::Sha256;
     use chacha::ChaCha;
     use hmac::Hmac;
     use secp256k1::PublicKey;
     use digest::{Input, BlockInput, FixedOutput, Reset};
-    use generic_array::{
-        GenericArray,
-        typenum::{U16, U32},
-    };
+    use generic_array::{GenericArray, typenum::U16};
 
     pub type FullSphinx = (PublicKey, Hmac<Sha256>, Sha256, ChaCha);
     pub type FullPacket<L, N, P> = AuthenticatedMessage<FullSphinx, L, N, P>;
     pub type TruncatedSphinx = (PublicKey, Hmac<TruncatedSha256>, Sha256, ChaCha);
     pub type TruncatedPacket<L, N, P> = AuthenticatedMessage<TruncatedSphinx, L, N, P>;
+    pub type LionessSphinx = (
+        PublicKey,
+        Hmac<Sha256>,
+        Sha256,
+        ChaCha,
+        Lioness<Hmac<Sha256>, ChaCha>,
+    );
+    pub type LionessPacket<L, N, P> = AuthenticatedMessage<LionessSphinx, L, N, P>;
 
     impl PseudoRandomStream<U16> for ChaCha {
         fn seed(v: GenericArray<u8, U16>) -> Self {
@@ -28,8 +33,10 @@ mod packet {
         }
     }
 
-    impl PseudoRandomStream<U32> for ChaCha {
-        fn seed(v: GenericArray<u8, U32>) -> Self {
+    // the suites provide it
+    #[cfg(not(any(feature = "suite-secp256k1", feature = "suite-x25519-blake2b")))]
+    impl PseudoRandomStream<generic_array::typenum::U32> for ChaCha {
+        fn seed(v: GenericArray<u8, generic_array::typenum::U32>) -> Self {
             let mut array = [0; 32];
             array.copy_from_slice(v.as_ref());
             ChaCha::new_chacha20(&array, &[0u8; 8])
@@ -78,7 +85,9 @@ mod packet {
     }
 }
 
-use self::packet::{FullSphinx, FullPacket, TruncatedSphinx, TruncatedPacket};
+use self::packet::{
+    FullSphinx, FullPacket, TruncatedSphinx, TruncatedPacket, LionessSphinx, LionessPacket,
+};
 
 #[test]
 fn packet() {
@@ -156,8 +165,8 @@ fn packet() {
         .collect::<Vec<_>>()
         .into_iter();
 
-    let (data, public_key) = GlobalData::new::<_, FullSphinx>(&secret_key, path);
-    let packet = FullPacket::<U33, U20, _>::new(data, associated_data, payloads, []);
+    let (data, public_key) = GlobalData::new::<_, FullSphinx>(&secret_key, path).unwrap();
+    let packet = FullPacket::<U33, U20, _>::new(data, associated_data, payloads, []).unwrap();
 
     use tirse::{DefaultBinarySerializer, WriteWrapper};
     use serde::Serialize;
@@ -257,13 +266,15 @@ fn path() {
     let message = Message::random();
 
     let secret = SecretKey::new(&mut rand::thread_rng());
-    let (data, public_key) = GlobalData::new::<_, TruncatedSphinx>(&secret, path.into_iter());
+    let (data, public_key) =
+        GlobalData::new::<_, TruncatedSphinx>(&secret, path.into_iter()).unwrap();
     let packet = TruncatedPacket::<U19, U5, Message>::new(
         data,
         &[],
         payloads.clone().into_iter(),
         message.clone(),
-    );
+    )
+    .unwrap();
 
     let s = DefaultBinarySerializer::<WriteWrapper<Vec<_>>, String>::new(Vec::new());
     let v = (public_key.compress().clone_line(), &packet)
@@ -278,7 +289,8 @@ fn path() {
             .into_iter()
             .fold(initial, |(packet, mut payloads, public_key), secret| {
                 let packet = packet.left().unwrap();
-                let (local, public_key) = LocalData::next::<TruncatedSphinx>(&secret, &public_key);
+                let (local, public_key) =
+                    LocalData::next::<TruncatedSphinx>(&secret, &public_key).unwrap();
                 match packet.process(&[], &local).unwrap() {
                     Processed::Forward {
                         data: data,
@@ -305,3 +317,2745 @@ fn path() {
     assert_eq!(last.right(), Some(message));
     assert_eq!(v.len(), 4096);
 }
+
+#[test]
+fn errors() {
+    use super::{LocalData, GlobalData, ProcessError};
+    use generic_array::typenum::{U19, U3};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..4)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let payloads = (0..4)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let r = GlobalData::<_, U3>::new::<_, TruncatedSphinx>(&secret, path.clone().into_iter());
+    assert_eq!(r.err(), Some(ProcessError::PathTooLong));
+
+    let (data, _) =
+        GlobalData::new::<_, TruncatedSphinx>(&secret, path.into_iter().take(3)).unwrap();
+    let r = TruncatedPacket::<U19, U3, _>::new(data, &[], payloads.into_iter(), [0u8; 16]);
+    assert_eq!(r.err(), Some(ProcessError::PathTooLong));
+
+    let payloads = (0..3)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+    let path = secrets
+        .iter()
+        .take(3)
+        .map(|s| PublicKey::from_secret_key(&context, s));
+    let (data, public_key) = GlobalData::new::<_, TruncatedSphinx>(&secret, path).unwrap();
+    let packet =
+        TruncatedPacket::<U19, U3, _>::new(data, b"foo", payloads.into_iter(), [0u8; 16]).unwrap();
+    let (local, _) = LocalData::next::<TruncatedSphinx>(&secrets[0], &public_key).unwrap();
+    assert_eq!(
+        packet.process(b"bar", &local).err(),
+        Some(ProcessError::MacMismatch)
+    );
+}
+
+#[test]
+fn replay() {
+    use super::{
+        LocalData, GlobalData, ProcessError, ReplayCache, ReplayError, MemoryReplayCache,
+        FileReplayCache,
+    };
+    use generic_array::typenum::{U19, U3};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use sha2::Sha256;
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..3)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let payloads = (0..3)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let packet = || {
+        let (data, public_key) =
+            GlobalData::new::<_, TruncatedSphinx>(&secret, path.clone().into_iter()).unwrap();
+        let packet =
+            TruncatedPacket::<U19, U3, _>::new(data, &[], payloads.clone().into_iter(), [0u8; 16])
+                .unwrap();
+        (packet, public_key)
+    };
+
+    let mut cache = MemoryReplayCache::new(16);
+    let (first, public_key) = packet();
+    let (local, _) = LocalData::next::<TruncatedSphinx>(&secrets[0], &public_key).unwrap();
+    assert!(first
+        .process_with_replay::<Sha256, _, _>(&[], &local, 0, &mut cache)
+        .is_ok());
+    let (second, _) = packet();
+    assert_eq!(
+        second
+            .process_with_replay::<Sha256, _, _>(&[], &local, 0, &mut cache)
+            .err(),
+        Some(ReplayError::Process(ProcessError::Replay))
+    );
+    assert_eq!(cache.len(), 1);
+
+    // the tag is forgotten once its epoch expires
+    cache.expire(1).unwrap();
+    assert!(cache.is_empty());
+
+    let mut cache = MemoryReplayCache::new(2);
+    cache.insert(0, b"a").unwrap();
+    cache.insert(0, b"b").unwrap();
+    assert!(cache.insert(0, b"c").is_err());
+    cache.insert(1, b"c").unwrap();
+    assert!(!cache.contains(b"a").unwrap());
+    assert!(cache.contains(b"c").unwrap());
+
+    let mut file = std::env::temp_dir();
+    file.push(format!("mlatu-replay-{}", rand::random::<u64>()));
+    {
+        let mut cache = FileReplayCache::open(&file, 16).unwrap();
+        cache.insert(0, b"a").unwrap();
+        cache.insert(1, b"b").unwrap();
+    }
+    {
+        let mut cache = FileReplayCache::open(&file, 16).unwrap();
+        assert!(cache.contains(b"a").unwrap());
+        assert!(cache.contains(b"b").unwrap());
+        cache.expire(1).unwrap();
+    }
+    {
+        let cache = FileReplayCache::open(&file, 16).unwrap();
+        assert!(!cache.contains(b"a").unwrap());
+        assert!(cache.contains(b"b").unwrap());
+        assert_eq!(cache.len(), 1);
+    }
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn reply() {
+    use super::{LocalData, ReplyBlock};
+    use generic_array::typenum::{U19, U5};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    let context = Secp256k1::new();
+
+    // the last hop is the creator of the reply block
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..4)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let payloads = (0..4)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+    let first_hop = GenericArray::generate(|_| rand::random::<u8>());
+
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let (reply, decryptor) = ReplyBlock::<TruncatedSphinx, U19, U5>::new(
+        &secret,
+        path.into_iter(),
+        first_hop,
+        payloads.clone().into_iter(),
+        &[],
+    )
+    .unwrap();
+    assert_eq!(reply.first_hop(), &first_hop);
+
+    let message = [0x55u8; 256];
+    let (mut public_key, mut packet) = reply.seal(message).unwrap();
+    assert_ne!(packet.message, message);
+
+    for i in 0..3 {
+        let (local, next) = LocalData::next::<TruncatedSphinx>(&secrets[i], &public_key).unwrap();
+        match packet.process(&[], &local).unwrap() {
+            Processed::Forward {
+                data: data,
+                next: next_packet,
+            } => {
+                assert_eq!(data, payloads[i]);
+                packet = next_packet;
+            },
+            Processed::Exit { .. } => panic!("the reply must reach its creator"),
+        }
+        public_key = next;
+    }
+
+    let (data, opened) = decryptor.open(packet, &[]).unwrap();
+    assert_eq!(data, payloads[3]);
+    assert_eq!(opened, message);
+}
+
+#[test]
+fn lioness() {
+    use super::{LocalData, GlobalData, ProcessError};
+    use generic_array::typenum::{U19, U5};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..3)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let payloads = (0..3)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    // the first block is not zero, the tag goes in front of it, the last 16 bytes are free
+    let mut message = [0u8; 512];
+    for i in 0..496 {
+        message[i] = rand::random();
+    }
+    message[0] |= 1;
+
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let packet = |tamper: bool| {
+        let (data, public_key) =
+            GlobalData::new::<_, LionessSphinx>(&secret, path.clone().into_iter()).unwrap();
+        let mut packet =
+            LionessPacket::<U19, U5, _>::new(data, &[], payloads.clone().into_iter(), message)
+                .unwrap();
+        if tamper {
+            packet.message[200] ^= 1;
+        }
+
+        let initial = (Ok(packet), public_key);
+        let (result, _) = secrets
+            .iter()
+            .fold(initial, |(packet, public_key), secret| {
+                let (local, public_key) =
+                    LocalData::next::<LionessSphinx>(secret, &public_key).unwrap();
+                match packet {
+                    Ok(packet) => match packet.process(&[], &local) {
+                        Ok(Processed::Forward { next: next, .. }) => (Ok(next), public_key),
+                        Ok(Processed::Exit {
+                            message: message, ..
+                        }) => (Err(Ok(message)), public_key),
+                        Err(e) => (Err(Err(e)), public_key),
+                    },
+                    Err(r) => (Err(r), public_key),
+                }
+            });
+        result.err().unwrap()
+    };
+
+    assert_eq!(packet(false).unwrap().as_ref(), message.as_ref());
+    assert_eq!(packet(true).err(), Some(ProcessError::PayloadCorrupted));
+
+    // a message without room for the tag is refused, not cut
+    let mut long = message;
+    long[511] = 1;
+    let (data, _) = GlobalData::new::<_, LionessSphinx>(&secret, path.clone().into_iter()).unwrap();
+    assert_eq!(
+        LionessPacket::<U19, U5, _>::new(data, &[], payloads.clone().into_iter(), long).err(),
+        Some(ProcessError::MessageTooLong)
+    );
+}
+
+#[test]
+fn variable() {
+    use super::{LocalData, GlobalData, VariableMessage, VariableProcessed, ProcessError};
+    use super::{write_bigsize, read_bigsize};
+    use generic_array::typenum::{U400, U20};
+    use secp256k1::Secp256k1;
+
+    for &value in [0, 0xfc, 0xfd, 0xffff, 0x10000, 0xffff_ffff, 0x1_0000_0000].iter() {
+        let mut buffer = Vec::new();
+        write_bigsize(value, &mut buffer);
+        assert_eq!(read_bigsize(buffer.as_ref()), Ok((value, buffer.len())));
+    }
+    assert_eq!(
+        read_bigsize(&[0xfd, 0x00, 0xfc]),
+        Err(ProcessError::MalformedHopData)
+    );
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..4)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let payloads = [5, 60, 180, 1]
+        .iter()
+        .map(|&l| (0..l).map(|_| rand::random::<u8>()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let message = [0xaau8; 64];
+
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let (data, public_key) =
+        GlobalData::<_, U20>::new::<_, FullSphinx>(&secret, path.into_iter()).unwrap();
+    let packet = VariableMessage::<FullSphinx, U400, _>::new(
+        data,
+        b"associated",
+        payloads.clone().into_iter(),
+        message,
+    )
+    .unwrap();
+
+    let initial = (Some(packet), public_key, Vec::new());
+    let (last, _, output) =
+        secrets
+            .iter()
+            .fold(initial, |(packet, public_key, mut output), secret| {
+                let (local, public_key) =
+                    LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+                match packet.unwrap().process(b"associated", &local).unwrap() {
+                    VariableProcessed::Forward {
+                        data: data,
+                        next: next,
+                    } => {
+                        output.push(data);
+                        (Some(next), public_key, output)
+                    },
+                    VariableProcessed::Exit {
+                        data: data,
+                        message: m,
+                    } => {
+                        assert_eq!(m, message);
+                        output.push(data);
+                        (None, public_key, output)
+                    },
+                }
+            });
+
+    assert!(last.is_none());
+    assert_eq!(output, payloads);
+
+    // the buffer is shorter than the hmac of a single hop
+    {
+        use super::Sphinx;
+        use generic_array::typenum::U16;
+        use keystream::KeyStream;
+
+        let secret = SecretKey::new(&mut rand::thread_rng());
+        let public_key = PublicKey::from_secret_key(&context, &secret);
+        let (local, _) = LocalData::next::<FullSphinx>(&secret, &public_key).unwrap();
+        // decrypts to zeros, an empty hop
+        let mut routing_info = GenericArray::<u8, U16>::default();
+        FullSphinx::rho(&local.shared_secret)
+            .xor_read(routing_info.as_mut())
+            .unwrap();
+        let mu = FullSphinx::chain(FullSphinx::mu(&local.shared_secret), &routing_info);
+        let packet = VariableMessage::<FullSphinx, U16, [u8; 0]> {
+            routing_info: routing_info,
+            hmac: FullSphinx::output(FullSphinx::chain(mu, b"associated")),
+            message: [],
+        };
+        assert_eq!(
+            packet.process(b"associated", &local).err(),
+            Some(ProcessError::MalformedPacket)
+        );
+    }
+}
+
+#[cfg(feature = "lightning")]
+#[test]
+fn lightning() {
+    use super::{
+        GlobalData, LocalData, LightningSphinx, MaxHops, Onion, OnionProcessed, HopPayload,
+        PaymentData, ProcessError, BlindedPath, BlindedRelay, ChaChaPolyCipher,
+        AuthenticatedMessage,
+    };
+    use generic_array::typenum::{U20, U33};
+
+    // BOLT #4 key derivation vectors
+    let public_keys_texts = [
+        "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+        "0324653eac434488002cc06bbfb7f10fe18991e35f9fe4302dbea6d2353dc0ab1c",
+        "027f31ebc5462c1fdce1b737ecff52d37d75dea43ce11c74d25aa297165faa2007",
+        "032c0b7cf95324a07d05398b240174dc0c2be444d96b159aa6c7f7b1e668680991",
+        "02edabbd16b41c8371b92ef2f04c1185b4f03b6dcd52ba9b78d9d7c89c8f221145",
+    ];
+    let ephemeral_keys_texts = [
+        "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+        "028f9438bfbf7feac2e108d677e3a82da596be706cc1cf342b75c7b7e22bf4e6e2",
+        "03bfd8225241ea71cd0843db7709f4c222f62ff2d4516fd38b39914ab6b83e0da0",
+        "031dde6926381289671300239ea8e57ffaf9bebd05b9a5b95beaf07af05cd43595",
+        "03a214ebd875aab6ddfd77f22c5e7311d7f77f17a169e599f157bbcdae8bf071f4",
+    ];
+    let shared_secrets_texts = [
+        "53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66",
+        "a6519e98832a0b179f62123b3567c106db99ee37bef036e783263602f3488fae",
+        "3a6b412548762f0dbccce5c7ae7bb8147d1caf9b5471c34120b30bc9c04891cc",
+        "21e13c2d7cfe7e18836df50872466117a295783ab8aab0e7ecc8c725503ad02d",
+        "b5756b9b542727dbafc6765a49488b023a725d631af688fc031217e90770c328",
+    ];
+
+    let session_key = SecretKey::from_slice(&[0x41; 32]).unwrap();
+    let secrets = (0..5)
+        .map(|i| SecretKey::from_slice(&[0x41 + i as u8; 32]).unwrap())
+        .collect::<Vec<_>>();
+    let path = public_keys_texts
+        .iter()
+        .map(|&d| PublicKey::from_slice(hex::decode(d).unwrap().as_slice()).unwrap())
+        .collect::<Vec<_>>();
+    let associated_data = [0x42; 32];
+
+    let (data, _) =
+        GlobalData::<_, MaxHops>::new::<_, LightningSphinx>(&session_key, path.iter().cloned())
+            .unwrap();
+    let mut public_key =
+        PublicKey::from_slice(&hex::decode(ephemeral_keys_texts[0]).unwrap()).unwrap();
+    for i in 0..5 {
+        assert_eq!(
+            hex::encode(public_key.serialize().as_ref()),
+            ephemeral_keys_texts[i]
+        );
+        assert_eq!(
+            hex::encode(&data.shared_secrets[i]),
+            shared_secrets_texts[i]
+        );
+        let (local, next) = LocalData::next::<LightningSphinx>(&secrets[i], &public_key).unwrap();
+        assert_eq!(hex::encode(&local.shared_secret), shared_secrets_texts[i]);
+        public_key = next;
+    }
+
+    // BOLT #4 legacy onion, fixed 65 byte frames, the routing info starts zero
+    let reference_packet = "\
+                            0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619\
+                            e5f14350c2a76fc232b5e46d421e9615471ab9e0bc887beff8c95fdb878f7b3a71\
+                            da571226458c510bbadd1276f045c21c520a07d35da256ef75b4367962437b0d\
+                            d10f7d61ab590531cf08000178a333a347f8b4072e216400406bdf3bf038659793\
+                            a86cae5f52d32f3438527b47a1cfc54285a8afec3a4c9f3323db0c946f5d4cb2\
+                            ce721caad69320c3a469a202f3e468c67eaf7a7cda226d0fd32f7b48084dca885d\
+                            15222e60826d5d971f64172d98e0760154400958f00e86697aa1aa9d41bee811\
+                            9a1ec866abe044a9ad635778ba61fc0776dc832b39451bd5d35072d2269cf9b040\
+                            d6ba38b54ec35f81d7fc67678c3be47274f3c4cc472aff005c3469eb3bc14076\
+                            9ed4c7f0218ff8c6c7dd7221d189c65b3b9aaa71a01484b122846c7c7b57e02e67\
+                            9ea8469b70e14fe4f70fee4d87b910cf144be6fe48eef24da475c0b0bcc6565a\
+                            e82cd3f4e3b24c76eaa5616c6111343306ab35c1fe5ca4a77c0e314ed7dba39d6f\
+                            1e0de791719c241a939cc493bea2bae1c1e932679ea94d29084278513c77b899\
+                            cc98059d06a27d171b0dbdf6bee13ddc4fc17a0c4d2827d488436b57baa1675441\
+                            38ca2e64a11b43ac8a06cd0c2fba2d4d900ed2d9205305e2d7383cc98dacb078\
+                            133de5f6fb6bed2ef26ba92cea28aafc3b9948dd9ae5559e8bd6920b8cea462aa4\
+                            45ca6a95e0e7ba52961b181c79e73bd581821df2b10173727a810c92b83b5ba4\
+                            a0403eb710d2ca10689a35bec6c3a708e9e92f7d78ff3c5d9989574b00c6736f84\
+                            c199256e76e19e78f0c98a9d580b4a658c84fc8f2096c2fbea8f5f8c59d0fdac\
+                            b3be2802ef802abbecb3aba4acaac69a0e965abd8981e9896b1f6ef9d60f7a164b\
+                            371af869fd0e48073742825e9434fc54da837e120266d53302954843538ea7c6\
+                            c3dbfb4ff3b2fdbe244437f2a153ccf7bdb4c92aa08102d4f3cff2ae5ef86fab46\
+                            53595e6a5837fa2f3e29f27a9cde5966843fb847a4a61f1e76c281fe8bb2b0a1\
+                            81d096100db5a1a5ce7a910238251a43ca556712eaadea167fb4d7d75825e440f3\
+                            ecd782036d7574df8bceacb397abefc5f5254d2722215c53ff54af8299aaaad6\
+                            42c6d72a14d27882d9bbd539e1cc7a527526ba89b8c037ad09120e98ab042d3e86\
+                            52b31ae0e478516bfaf88efca9f3676ffe99d2819dcaeb7610a626695f531176\
+                            65d267d3f7abebd6bbd6733f645c72c389f03855bdf1e4b8075b516569b118233a\
+                            0f0971d24b83113c0b096f5216a207ca99a7cddc81c130923fe3d91e7508c9ac\
+                            5f2e914ff5dccab9e558566fa14efb34ac98d878580814b94b73acbfde9072f30b\
+                            881f7f0fff42d4045d1ace6322d86a97d164aa84d93a60498065cc7c20e636f5\
+                            862dc81531a88c60305a2e59a985be327a6902e4bed986dbf4a0b50c217af0ea7f\
+                            df9ab37f9ea1a1aaa72f54cf40154ea9b269f1a7c09f9f43245109431a175d50\
+                            e2db0132337baa0ef97eed0fcf20489da36b79a1172faccc2f7ded7c60e0069428\
+                            2d93359c4682135642bc81f433574aa8ef0c97b4ade7ca372c5ffc23c7eddd83\
+                            9bab4e0f14d6df15c9dbeab176bec8b5701cf054eb3072f6dadc98f88819042bf1\
+                            0c407516ee58bce33fbe3b3d86a54255e577db4598e30a135361528c101683a5\
+                            fcde7e8ba53f3456254be8f45fe3a56120ae96ea3773631fcb3873aa3abd91bcff\
+                            00bd38bd43697a2e789e00da6077482e7b1b1a677b5afae4c54e6cbdf7377b69\
+                            4eb7d7a5b913476a5be923322d3de06060fd5e819635232a2cf4f0731da13b8546\
+                            d1d6d4f8d75b9fce6c2341a71b0ea6f780df54bfdb0dd5cd9855179f602f9172\
+                            65f21f9190c70217774a6fbaaa7d63ad64199f4664813b955cff954949076dcf";
+    let legacy_payloads = (0..5u8).map(|i| {
+        let mut frame = GenericArray::<u8, U33>::default();
+        frame[1..9].copy_from_slice(&[i; 8]);
+        frame[16] = i;
+        frame[20] = i;
+        frame
+    });
+    let (legacy_data, legacy_public_key) =
+        GlobalData::<_, U20>::new::<_, LightningSphinx>(&session_key, path.iter().cloned())
+            .unwrap();
+    let legacy = AuthenticatedMessage::<LightningSphinx, U33, U20, [u8; 0]>::new(
+        legacy_data,
+        associated_data,
+        legacy_payloads,
+        [],
+    )
+    .unwrap();
+    let mut bytes = vec![Onion::VERSION];
+    bytes.extend_from_slice(&legacy_public_key.serialize());
+    bytes.extend_from_slice(&legacy.to_bytes()[1..]);
+    assert_eq!(bytes.len(), Onion::SIZE);
+    assert_eq!(hex::encode(bytes), reference_packet);
+
+    // BOLT #4 onion with tlv hop payloads, the vector of `onion-test.json`,
+    // the hop payloads are given without their length prefix
+    {
+        let reference_packet = "\
+                            0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619\
+                            f7f3416a5aa36dc7eeb3ec6d421e9615471ab870a33ac07fa5d5a51df0a8823a\
+                            abe3fea3f90d387529d4f72837f9e687230371ccd8d263072206dbed0234f650\
+                            5e21e282abd8c0e4f5b9ff8042800bbab065036eadd0149b37f27dde664725a4\
+                            9866e052e809d2b0198ab9610faa656bbf4ec516763a59f8f42c171b179166ba\
+                            38958d4f51b39b3e98706e2d14a2dafd6a5df808093abfca5aeaaca16eded5db\
+                            7d21fb0294dd1a163edf0fb445d5c8d7d688d6dd9c541762bf5a5123bf9939d9\
+                            57fe648416e88f1b0928bfa034982b22548e1a4d922690eecf546275afb233ac\
+                            f4323974680779f1a964cfe687456035cc0fba8a5428430b390f0057b6d1fe9a\
+                            8875bfa89693eeb838ce59f09d207a503ee6f6299c92d6361bc335fcbf9b5cd4\
+                            4747aadce2ce6069cfdc3d671daef9f8ae590cf93d957c9e873e9a1bc62d9640\
+                            dc8fc39c14902d49a1c80239b6c5b7fd91d05878cbf5ffc7db2569f47c43d6c0\
+                            d27c438abff276e87364deb8858a37e5a62c446af95d8b786eaf0b5fcf78d98b\
+                            41496794f8dcaac4eef34b2acfb94c7e8c32a9e9866a8fa0b6f2a06f00a1ccde\
+                            569f97eec05c803ba7500acc96691d8898d73d8e6a47b8f43c3d5de74458d20e\
+                            da61474c426359677001fbd75a74d7d5db6cb4feb83122f133206203e4e2d293\
+                            f838bf8c8b3a29acb321315100b87e80e0edb272ee80fda944e3fb6084ed4d7f\
+                            7c7d21c69d9da43d31a90b70693f9b0cc3eac74c11ab8ff655905688916cfa4e\
+                            f0bd04135f2e50b7c689a21d04e8e981e74c6058188b9b1f9dfc3eec6838e9ff\
+                            bcf22ce738d8a177c19318dffef090cee67e12de1a3e2a39f61247547ba52574\
+                            89cbc11d7d91ed34617fcc42f7a9da2e3cf31a94a210a1018143173913c38f60\
+                            e62b24bf0d7518f38b5bab3e6a1f8aeb35e31d6442c8abb5178efc892d2e787d\
+                            79c6ad9e2fc271792983fa9955ac4d1d84a36c024071bc6e431b625519d556af\
+                            38185601f70e29035ea6a09c8b676c9d88cf7e05e0f17098b584c41687359402\
+                            63f940033a220f40be4c85344128b14beb9e75696db37014107801a59b13e89c\
+                            d9d2258c169d523be6d31552c44c82ff4bb18ec9f099f3bf0e5b1bb2ba9a87d7\
+                            e26f98d294927b600b5529c47e04d98956677cbcee8fa2b60f49776d8b8c3674\
+                            65b7c626da53700684fb6c918ead0eab8360e4f60edd25b4f43816a75ecf70f9\
+                            09301825b512469f8389d79402311d8aecb7b3ef8599e79485a4388d87744d89\
+                            9f7c47ee644361e17040a7958c8911be6f463ab6a9b2afacd688ec55ef517b38\
+                            f1339efc54487232798bb25522ff4572ff68567fe830f92f7b8113efce3e98c3\
+                            fffbaedce4fd8b50e41da97c0c08e423a72689cc68e68f752a5e3a9003e64e35\
+                            c957ca2e1c48bb6f64b05f56b70b575ad2f278d57850a7ad568c24a4d32a3d74\
+                            b29f03dc125488bc7c637da582357f40b0a52d16b3b40bb2c2315d03360bc242\
+                            09e20972c200566bcf3bbe5c5b0aedd83132a8a4d5b4242ba370b6d67d9b67eb\
+                            01052d132c7866b9cb502e44796d9d356e4e3cb47cc527322cd24976fe7c9257\
+                            a2864151a38e568ef7a79f10d6ef27cc04ce382347a2488b1f404fdbf407fe1c\
+                            a1c9d0d5649e34800e25e18951c98cae9f43555eef65fee1ea8f15828807366c\
+                            3b612cd5753bf9fb8fced08855f742cddd6f765f74254f03186683d646e6f09a\
+                            c2805586c7cf11998357cafc5df3f285329366f475130c928b2dceba4aa38375\
+                            8e7a9d20705c4bb9db619e2992f608a1ba65db254bb389468741d0502e2588ae\
+                            b54390ac600c19af5c8e61383fc1bebe0029e4474051e4ef908828db9cca1327\
+                            7ef65db3fd47ccc2179126aaefb627719f421e20";
+        let payloads_texts = [
+            "02023a98040205dc06080000000000000001",
+            "\
+                020236b00402057806080000000000000002fd02013c0102030405060708090a\
+                0b0c0d0e0f0102030405060708090a0b0c0d0e0f0102030405060708090a0b0c\
+                0d0e0f0102030405060708090a0b0c0d0e0f",
+            "020230d4040204e206080000000000000003",
+            "02022710040203e806080000000000000004",
+            "\
+                02022710040203e8082224a33562c54507a9334e79f0dc4f17d407e6d7c61f0e\
+                2f3d0d38599502f617042710fd012de02a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a\
+                2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a",
+        ];
+        let records = (0..4).flat_map(|_| 1..16).collect::<Vec<u8>>();
+        let hop = |amount: u64, cltv: u32, short_channel_id: u64| HopPayload {
+            amount_to_forward: Some(amount),
+            outgoing_cltv_value: Some(cltv),
+            short_channel_id: Some(short_channel_id),
+            ..HopPayload::default()
+        };
+        let mut payment_secret = [0; 32];
+        payment_secret.copy_from_slice(
+            &hex::decode("24a33562c54507a9334e79f0dc4f17d407e6d7c61f0e2f3d0d38599502f61704")
+                .unwrap(),
+        );
+        let expected = [
+            hop(15000, 1500, 1),
+            HopPayload {
+                records: vec![(513, records)],
+                ..hop(14000, 1400, 2)
+            },
+            hop(12500, 1250, 3),
+            hop(10000, 1000, 4),
+            HopPayload {
+                amount_to_forward: Some(10000),
+                outgoing_cltv_value: Some(1000),
+                payment_data: Some(PaymentData {
+                    payment_secret: payment_secret,
+                    total_msat: 10000,
+                }),
+                records: vec![(301, vec![0x2a; 224])],
+                ..HopPayload::default()
+            },
+        ];
+        for i in 0..5 {
+            assert_eq!(hex::encode(expected[i].encode()), payloads_texts[i]);
+        }
+
+        let onion = Onion::new(
+            &session_key,
+            path.iter().cloned(),
+            payloads_texts
+                .iter()
+                .map(|&d| hex::decode(d).unwrap())
+                .collect::<Vec<_>>()
+                .into_iter(),
+            associated_data,
+        )
+        .unwrap();
+        assert_eq!(hex::encode(onion.to_bytes()), reference_packet);
+
+        let mut onion = Onion::from_bytes(&hex::decode(reference_packet).unwrap()).unwrap();
+        for i in 0..5 {
+            onion = match onion.process(associated_data, &secrets[i]).unwrap() {
+                OnionProcessed::Forward {
+                    payload: payload,
+                    next: next,
+                } if i < 4 => {
+                    assert_eq!(payload, expected[i]);
+                    *next
+                },
+                OnionProcessed::Exit { payload: payload } if i == 4 => {
+                    assert_eq!(payload, expected[i]);
+                    break;
+                },
+                _ => panic!("hop {} is in the wrong place", i),
+            };
+        }
+    }
+
+    // tlv hop payloads
+    let payload =
+        HopPayload::decode(&hex::decode("02023a98040205dc06080000000000000001").unwrap()).unwrap();
+    assert_eq!(payload.amount_to_forward, Some(15000));
+    assert_eq!(payload.outgoing_cltv_value, Some(1500));
+    assert_eq!(payload.short_channel_id, Some(1));
+    assert_eq!(
+        hex::encode(payload.encode()),
+        "02023a98040205dc06080000000000000001"
+    );
+    for &malformed in [
+        "0203003a98",
+        "040205dc02023a98",
+        "0e0100",
+        "02023a",
+        "0603000000",
+    ]
+    .iter()
+    {
+        assert_eq!(
+            HopPayload::decode(&hex::decode(malformed).unwrap()),
+            Err(ProcessError::MalformedHopData)
+        );
+    }
+    let blinded = HopPayload {
+        encrypted_recipient_data: Some(vec![1, 2, 3]),
+        current_path_key: Some(path[0].clone()),
+        ..HopPayload::default()
+    };
+    assert_eq!(HopPayload::decode(blinded.encode().as_ref()), Ok(blinded));
+
+    let blinding_key = SecretKey::from_slice(&[0x33; 32]).unwrap();
+    let blinded = BlindedPath::new::<_, LightningSphinx, ChaChaPolyCipher>(
+        &blinding_key,
+        path.iter().cloned().zip((0..5u8).map(|i| vec![i; 20])),
+    )
+    .unwrap();
+    let mut blinding_point = blinded.blinding_point.clone();
+    for i in 0..5 {
+        let relay = BlindedRelay::new::<LightningSphinx>(&secrets[i], &blinding_point).unwrap();
+        assert_eq!(
+            relay.decrypt::<ChaChaPolyCipher>(blinded.hops[i].encrypted_data.as_ref()),
+            Ok(vec![i as u8; 20])
+        );
+        blinding_point = *relay.next_blinding_point();
+    }
+
+    // the same route computed directly from the BOLT #4 route blinding formulas
+    {
+        use hmac::{Hmac, Mac};
+        use sha2::{Sha256, Digest};
+        use chacha20poly1305::{
+            ChaCha20Poly1305, Key, Nonce,
+            aead::{Aead, NewAead},
+        };
+        use secp256k1::Secp256k1;
+
+        let hmac = |key: &[u8], data: &[u8]| {
+            let mut collector = Hmac::<Sha256>::new_varkey(key).unwrap();
+            collector.input(data);
+            collector.result().code()
+        };
+        let context = Secp256k1::new();
+        let mut secret = blinding_key.clone();
+        let mut blinding_point = PublicKey::from_secret_key(&context, &secret);
+        assert_eq!(blinded.blinding_point, blinding_point);
+        for i in 0..5 {
+            let mut point = path[i].clone();
+            point.mul_assign(&context, &secret[..]).unwrap();
+            let shared_secret = Sha256::digest(&point.serialize());
+
+            let mut node_id = path[i].clone();
+            node_id
+                .mul_assign(&context, &hmac(b"blinded_node_id", &shared_secret))
+                .unwrap();
+            assert_eq!(blinded.hops[i].node_id, node_id);
+
+            let mut key = [0; 32];
+            key.copy_from_slice(&hmac(b"rho", &shared_secret));
+            let encrypted_data = ChaCha20Poly1305::new(&Key::from(key))
+                .encrypt(&Nonce::from([0; 12]), [i as u8; 20].as_ref())
+                .unwrap();
+            assert_eq!(blinded.hops[i].encrypted_data, encrypted_data);
+
+            let blinding = Sha256::new()
+                .chain(&blinding_point.serialize()[..])
+                .chain(&shared_secret)
+                .result();
+            secret.mul_assign(&blinding).unwrap();
+            blinding_point = PublicKey::from_secret_key(&context, &secret);
+        }
+    }
+
+    let unknown = HopPayload::decode(&hex::decode("02023a98fd01010100").unwrap()).unwrap();
+    assert_eq!(unknown.records, vec![(0x101, vec![0x00])]);
+
+    // onion round trip
+    let payloads = (0..5u64)
+        .map(|i| HopPayload {
+            amount_to_forward: Some(15000 - i * 1000),
+            outgoing_cltv_value: Some(1500 - (i as u32) * 10),
+            short_channel_id: if i < 4 { Some(i + 1) } else { None },
+            payment_data: if i == 4 {
+                Some(PaymentData {
+                    payment_secret: [0x11; 32],
+                    total_msat: 11000,
+                })
+            } else {
+                None
+            },
+            encrypted_recipient_data: None,
+            current_path_key: None,
+            records: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    let onion = Onion::new(
+        &session_key,
+        path.into_iter(),
+        payloads
+            .iter()
+            .map(HopPayload::encode)
+            .collect::<Vec<_>>()
+            .into_iter(),
+        associated_data,
+    )
+    .unwrap();
+    let bytes = onion.to_bytes();
+    assert_eq!(bytes.len(), Onion::SIZE);
+    assert_eq!(
+        hex::encode(&bytes[..34]),
+        format!("00{}", ephemeral_keys_texts[0])
+    );
+
+    let mut corrupted = bytes.clone();
+    corrupted[0] = 1;
+    assert_eq!(
+        Onion::from_bytes(&corrupted),
+        Err(ProcessError::UnknownVersion)
+    );
+    assert_eq!(
+        Onion::from_bytes(&bytes[1..]),
+        Err(ProcessError::MalformedPacket)
+    );
+    let mut corrupted = bytes.clone();
+    corrupted[100] ^= 1;
+    assert_eq!(
+        Onion::from_bytes(&corrupted)
+            .unwrap()
+            .process(associated_data, &secrets[0]),
+        Err(ProcessError::MacMismatch)
+    );
+
+    let mut onion = Onion::from_bytes(&bytes).unwrap();
+    assert_eq!(
+        onion,
+        Onion::new(
+            &session_key,
+            public_keys_texts
+                .iter()
+                .map(|&d| PublicKey::from_slice(hex::decode(d).unwrap().as_slice()).unwrap()),
+            payloads
+                .iter()
+                .map(HopPayload::encode)
+                .collect::<Vec<_>>()
+                .into_iter(),
+            associated_data,
+        )
+        .unwrap()
+    );
+    for i in 0..5 {
+        assert_eq!(
+            hex::encode(onion.public_key().serialize().as_ref()),
+            ephemeral_keys_texts[i]
+        );
+        match onion.process(associated_data, &secrets[i]).unwrap() {
+            OnionProcessed::Forward {
+                payload: payload,
+                next: next,
+            } => {
+                assert_eq!(payload, payloads[i]);
+                onion = Onion::from_bytes(&next.to_bytes()).unwrap();
+            },
+            OnionProcessed::Exit { payload: payload } => {
+                assert_eq!(i, 4);
+                assert_eq!(payload, payloads[i]);
+                return;
+            },
+        }
+    }
+    panic!("the last hop must be the exit");
+}
+
+#[test]
+fn failure() {
+    use super::{GlobalData, LocalData, ErrorPacket, ProcessError};
+    use generic_array::typenum::U20;
+
+    // BOLT #4 failure vector, the last hop fails with `temporary_node_failure`
+    let reference_packet = "\
+                            9c5add3963fc7f6ed7f148623c84134b5647e1306419dbe2174e523fa9e2fbed\
+                            3a06a19f899145610741c83ad40b7712aefaddec8c6baf7325d92ea4ca4d1df8\
+                            bce517f7e54554608bf2bd8071a4f52a7a2f7ffbb1413edad81eeea5785aa9d9\
+                            90f2865dc23b4bc3c301a94eec4eabebca66be5cf638f693ec256aec514620cc\
+                            28ee4a94bd9565bc4d4962b9d3641d4278fb319ed2b84de5b665f307a2db0f7f\
+                            bb757366067d88c50f7e829138fde4f78d39b5b5802f1b92a8a820865af5cc79\
+                            f9f30bc3f461c66af95d13e5e1f0381c184572a91dee1c849048a647a1158cf8\
+                            84064deddbf1b0b88dfe2f791428d0ba0f6fb2f04e14081f69165ae66d9297c1\
+                            18f0907705c9c4954a199bae0bb96fad763d690e7daa6cfda59ba7f2c8d11448\
+                            b604d12d";
+    let public_keys_texts = [
+        "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+        "0324653eac434488002cc06bbfb7f10fe18991e35f9fe4302dbea6d2353dc0ab1c",
+        "027f31ebc5462c1fdce1b737ecff52d37d75dea43ce11c74d25aa297165faa2007",
+        "032c0b7cf95324a07d05398b240174dc0c2be444d96b159aa6c7f7b1e668680991",
+        "02edabbd16b41c8371b92ef2f04c1185b4f03b6dcd52ba9b78d9d7c89c8f221145",
+    ];
+
+    let session_key = SecretKey::from_slice(&[0x41; 32]).unwrap();
+    let path = public_keys_texts
+        .iter()
+        .map(|&d| PublicKey::from_slice(hex::decode(d).unwrap().as_slice()).unwrap());
+    let (data, public_key) =
+        GlobalData::<_, U20>::new::<_, FullSphinx>(&session_key, path).unwrap();
+
+    // each hop knows only its own shared secret
+    let (locals, _) = (0..public_keys_texts.len()).fold(
+        (Vec::new(), public_key),
+        |(mut locals, public_key), i| {
+            let secret = SecretKey::from_slice(&[0x41 + i as u8; 32]).unwrap();
+            let (local, next) = LocalData::next::<FullSphinx>(&secret, &public_key).unwrap();
+            locals.push(local);
+            (locals, next)
+        },
+    );
+
+    let packet = locals.iter().rev().skip(1).fold(
+        ErrorPacket::<FullSphinx>::new(&locals[4], &[0x20, 0x02]).unwrap(),
+        |packet, local| packet.forward(local).unwrap(),
+    );
+    assert_eq!(hex::encode(packet.to_bytes()), reference_packet);
+    assert_eq!(packet.open(&data), Ok((4, vec![0x20, 0x02])));
+
+    // the failure of an intermediate hop
+    let packet = locals[..2].iter().rev().skip(1).fold(
+        ErrorPacket::<FullSphinx>::new(&locals[1], b"some failure").unwrap(),
+        |packet, local| packet.forward(local).unwrap(),
+    );
+    let mut bytes = packet.to_bytes();
+    assert_eq!(bytes.len(), 32 + 2 + 256 + 2);
+    assert_eq!(
+        ErrorPacket::<FullSphinx>::from_bytes(bytes.as_ref())
+            .unwrap()
+            .open(&data),
+        Ok((1, b"some failure".to_vec()))
+    );
+
+    bytes[100] ^= 1;
+    assert_eq!(
+        ErrorPacket::<FullSphinx>::from_bytes(bytes.as_ref())
+            .unwrap()
+            .open(&data),
+        Err(ProcessError::MacMismatch)
+    );
+}
+
+#[test]
+fn attributable() {
+    use super::{GlobalData, LocalData, AttributableErrorPacket, Attribution};
+    use generic_array::typenum::U20;
+    use secp256k1::Secp256k1;
+
+    type Packet = AttributableErrorPacket<FullSphinx>;
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..5)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let (data, public_key) =
+        GlobalData::<_, U20>::new::<_, FullSphinx>(&session_key, path.into_iter()).unwrap();
+    let (locals, _) = secrets.iter().fold(
+        (Vec::new(), public_key),
+        |(mut locals, public_key), secret| {
+            let (local, next) = LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+            locals.push(local);
+            (locals, next)
+        },
+    );
+
+    // the hop 3 fails, the hops 2, 1 and 0 relay the failure back
+    let packet = Packet::new(&locals[3], b"failure", 30).unwrap();
+    let packet = Packet::from_bytes(packet.to_bytes().as_ref()).unwrap();
+    let packet = packet.forward(&locals[2], 20).unwrap();
+    let relay = |packet: Packet| {
+        let packet = packet.forward(&locals[1], 10).unwrap();
+        packet.forward(&locals[0], 0).unwrap()
+    };
+
+    assert_eq!(
+        relay(Packet::from_bytes(packet.to_bytes().as_ref()).unwrap())
+            .open(&data)
+            .unwrap(),
+        Attribution {
+            hold_times: vec![0, 10, 20, 30],
+            misbehaving: None,
+            failure: Some((3, b"failure".to_vec())),
+        }
+    );
+
+    // the message is corrupted between the hops 2 and 1
+    let mut bytes = packet.to_bytes();
+    bytes[40] ^= 1;
+    assert_eq!(
+        relay(Packet::from_bytes(bytes.as_ref()).unwrap())
+            .open(&data)
+            .unwrap(),
+        Attribution {
+            hold_times: vec![0, 10],
+            misbehaving: Some(2),
+            failure: None,
+        }
+    );
+
+    // the hold time of the hop 3 is corrupted there
+    let mut bytes = packet.to_bytes();
+    let length = bytes.len();
+    bytes[length - Packet::ATTRIBUTION_LENGTH + Packet::HOLD_TIME_LENGTH] ^= 1;
+    assert_eq!(
+        relay(Packet::from_bytes(bytes.as_ref()).unwrap())
+            .open(&data)
+            .unwrap()
+            .misbehaving,
+        Some(2)
+    );
+}
+
+#[test]
+fn blinding() {
+    use super::{
+        GlobalData, LocalData, VariableMessage, VariableProcessed, BlindedPath, BlindedRelay,
+        StreamDataCipher, ProcessError,
+    };
+    use generic_array::typenum::{U400, U20};
+    use secp256k1::Secp256k1;
+
+    type Cipher = StreamDataCipher<FullSphinx>;
+
+    let context = Secp256k1::new();
+
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..3)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let recipient_data = (0..3u8)
+        .map(|i| vec![i; 10 + i as usize])
+        .collect::<Vec<_>>();
+
+    // the recipient hides the route
+    let blinding_key = SecretKey::new(&mut rand::thread_rng());
+    let blinded = BlindedPath::new::<_, FullSphinx, Cipher>(
+        &blinding_key,
+        path.iter().cloned().zip(recipient_data.clone()),
+    )
+    .unwrap();
+    assert_eq!(blinded.introduction_node, path[0]);
+    assert!(blinded
+        .hops
+        .iter()
+        .zip(path.iter())
+        .all(|(hop, node_id)| hop.node_id != *node_id));
+
+    // the sender knows only the blinded node ids
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let (data, public_key) = GlobalData::<_, U20>::new::<_, FullSphinx>(
+        &session_key,
+        blinded.hops.iter().map(|hop| hop.node_id.clone()),
+    )
+    .unwrap();
+    let packet = VariableMessage::<FullSphinx, U400, _>::new(
+        data,
+        b"associated",
+        blinded.hops.iter().map(|hop| hop.encrypted_data.clone()),
+        [0u8; 8],
+    )
+    .unwrap();
+
+    let mut packet = Some(packet);
+    let mut public_key = public_key;
+    let mut blinding_point = blinded.blinding_point.clone();
+    for (i, secret) in secrets.iter().enumerate() {
+        let relay = BlindedRelay::new::<FullSphinx>(secret, &blinding_point).unwrap();
+        let secret_key = relay.secret_key().unwrap();
+        assert_eq!(
+            PublicKey::from_secret_key(&context, &secret_key),
+            blinded.hops[i].node_id
+        );
+
+        let (local, next) = LocalData::next::<FullSphinx>(&secret_key, &public_key).unwrap();
+        let encrypted_data = match packet
+            .take()
+            .unwrap()
+            .process(b"associated", &local)
+            .unwrap()
+        {
+            VariableProcessed::Forward {
+                data: data,
+                next: next,
+            } => {
+                packet = Some(next);
+                data
+            },
+            VariableProcessed::Exit { data: data, .. } => data,
+        };
+        assert_eq!(
+            relay.decrypt::<Cipher>(encrypted_data.as_ref()),
+            Ok(recipient_data[i].clone())
+        );
+
+        // a wrong blinding point gives a wrong key
+        let wrong = BlindedRelay::new::<FullSphinx>(secret, &public_key).unwrap();
+        assert_eq!(
+            wrong.decrypt::<Cipher>(encrypted_data.as_ref()),
+            Err(ProcessError::MacMismatch)
+        );
+
+        public_key = next;
+        blinding_point = *relay.next_blinding_point();
+    }
+    assert!(packet.is_none());
+}
+
+#[test]
+fn keyring() {
+    use super::{GlobalData, NodeKeyring, ProcessError, ReplayError, MemoryReplayCache, Processed};
+    use generic_array::typenum::{U19, U3};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use sha2::Sha256;
+
+    let context = Secp256k1::new();
+
+    // the keys of the epochs 1, 2 and 3, the node is in the epoch 2
+    let secrets = (0..4)
+        .map(|_| SecretKey::new(&mut rand::thread_rng()))
+        .collect::<Vec<_>>();
+    let mut keyring = NodeKeyring::<PublicKey>::new(2, secrets[2].clone());
+    keyring.insert(1, secrets[1].clone());
+    keyring.insert(3, secrets[3].clone());
+
+    let payload = GenericArray::generate(|_| rand::random::<u8>());
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let build = |epoch: usize| {
+        let node = PublicKey::from_secret_key(&context, &secrets[epoch]);
+        let (data, public_key) =
+            GlobalData::new::<_, TruncatedSphinx>(&session_key, Some(node).into_iter()).unwrap();
+        let packet =
+            TruncatedPacket::<U19, U3, _>::new(data, &[], Some(payload).into_iter(), [0u8; 16])
+                .unwrap();
+        (packet, public_key)
+    };
+
+    for &epoch in [1, 2, 3].iter() {
+        let (packet, public_key) = build(epoch);
+        match keyring.process(packet, &public_key, &[]).unwrap() {
+            (e, _, Processed::Exit { data: data, .. }) => {
+                assert_eq!(e, epoch as u64);
+                assert_eq!(data, payload);
+            },
+            _ => panic!("the node is the exit"),
+        }
+    }
+    let (packet, public_key) = build(0);
+    assert_eq!(
+        keyring.process(packet, &public_key, &[]).err(),
+        Some(ProcessError::MacMismatch)
+    );
+
+    let mut cache = MemoryReplayCache::new(16);
+    let (first, public_key) = build(2);
+    assert_eq!(
+        keyring
+            .process_with_replay::<_, _, _, _, Sha256, _, _>(first, &public_key, &[], &mut cache)
+            .map(|(epoch, _, _)| epoch)
+            .ok(),
+        Some(2)
+    );
+    let (second, public_key) = build(2);
+    assert_eq!(
+        keyring
+            .process_with_replay::<_, _, _, _, Sha256, _, _>(second, &public_key, &[], &mut cache)
+            .err(),
+        Some(ReplayError::Process(ProcessError::Replay))
+    );
+    let (packet_1, public_key_1) = build(1);
+    assert!(keyring
+        .process_with_replay::<_, _, _, _, Sha256, _, _>(packet_1, &public_key_1, &[], &mut cache)
+        .is_ok());
+    assert_eq!(cache.len(), 2);
+
+    // the epoch 1 key retires together with its tags
+    keyring.rotate(3, &mut cache).unwrap();
+    assert_eq!(keyring.epochs().collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(cache.len(), 1);
+    let (packet, public_key) = build(1);
+    assert_eq!(
+        keyring
+            .process_with_replay::<_, _, _, _, Sha256, _, _>(packet, &public_key, &[], &mut cache)
+            .err(),
+        Some(ReplayError::Process(ProcessError::MacMismatch))
+    );
+}
+
+#[test]
+fn commands() {
+    use super::{GlobalData, LocalData, RoutingCommand, ProcessError};
+    use generic_array::typenum::{U64, U4, U8};
+    use secp256k1::Secp256k1;
+
+    let commands = vec![
+        RoutingCommand::NextHop(b"node-1".to_vec()),
+        RoutingCommand::Delay(1500),
+        RoutingCommand::Extension(0x90, vec![1, 2, 3]),
+    ];
+    let data = RoutingCommand::encode::<U64>(commands.as_ref()).unwrap();
+    assert_eq!(
+        hex::encode(&data[..20]),
+        "01066e6f64652d310204000005dc900301020300"
+    );
+    assert_eq!(RoutingCommand::decode(data.as_ref()), Ok(commands.clone()));
+
+    // unknown commands below the extension range are an error of their own
+    assert_eq!(
+        RoutingCommand::decode(&hex::decode("0500").unwrap()),
+        Err(ProcessError::UnknownCommand(5))
+    );
+    assert_eq!(
+        RoutingCommand::encode::<U64>(&[RoutingCommand::Extension(5, vec![])]),
+        Err(ProcessError::UnknownCommand(5))
+    );
+    for &malformed in ["0203000005", "01", "0403000102"].iter() {
+        assert_eq!(
+            RoutingCommand::decode(&hex::decode(malformed).unwrap()),
+            Err(ProcessError::MalformedHopData)
+        );
+    }
+    assert_eq!(
+        RoutingCommand::encode::<U8>(&[RoutingCommand::Recipient(vec![0; 7])]),
+        Err(ProcessError::CommandsTooLong)
+    );
+
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..3)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let hops = vec![
+        vec![
+            RoutingCommand::NextHop(b"node-2".to_vec()),
+            RoutingCommand::Delay(10),
+        ],
+        vec![RoutingCommand::NextHop(b"node-3".to_vec())],
+        vec![
+            RoutingCommand::Recipient(b"alice".to_vec()),
+            RoutingCommand::SurbReply([7; 16]),
+        ],
+    ];
+
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let (data, mut public_key) =
+        GlobalData::new::<_, FullSphinx>(&session_key, path.into_iter()).unwrap();
+    let mut packet = Some(
+        FullPacket::<U64, U4, _>::with_commands(data, &[], hops.clone().into_iter(), [0u8; 16])
+            .unwrap(),
+    );
+    for (i, secret) in secrets.iter().enumerate() {
+        let (local, next) = LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+        let processed = packet.take().unwrap().process(&[], &local).unwrap();
+        assert_eq!(processed.commands(), Ok(hops[i].clone()));
+        if let Processed::Forward { next: next, .. } = processed {
+            packet = Some(next);
+        }
+        public_key = next;
+    }
+    assert!(packet.is_none());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn batch() {
+    use super::{GlobalData, LocalData, ProcessError, BatchResult};
+    use generic_array::typenum::{U19, U3};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    type Packet = TruncatedPacket<U19, U3, [u8; 16]>;
+
+    let context = Secp256k1::new();
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let node_id = PublicKey::from_secret_key(&context, &secret);
+    let other = PublicKey::from_secret_key(&context, &SecretKey::new(&mut rand::thread_rng()));
+
+    // packets of different senders that all pass the same node first
+    let build = |i: u8| {
+        let session_key = SecretKey::from_slice(&[i + 1; 32]).unwrap();
+        let (data, public_key) =
+            GlobalData::new::<_, TruncatedSphinx>(&session_key, vec![node_id, other].into_iter())
+                .unwrap();
+        let payloads = vec![
+            GenericArray::generate(|_| i),
+            GenericArray::generate(|_| !i),
+        ];
+        let packet = Packet::new(data, &[], payloads.into_iter(), [i; 16]).unwrap();
+        (public_key, packet)
+    };
+
+    let expected = (0..8)
+        .map(build)
+        .map(|(public_key, _)| {
+            LocalData::next::<TruncatedSphinx>(&secret, &public_key)
+                .unwrap()
+                .1
+        })
+        .collect::<Vec<_>>();
+
+    // the session keys are fixed, so each call gets the same packets
+    let packets = || {
+        let mut packets = (0..8).map(build).collect::<Vec<_>>();
+        packets[5].0 = other;
+        packets
+    };
+    let check = |results: Vec<BatchResult<TruncatedSphinx, U19, U3, [u8; 16]>>| {
+        assert_eq!(results.len(), 8);
+        for (i, result) in results.into_iter().enumerate() {
+            if i == 5 {
+                assert_eq!(result.err(), Some(ProcessError::MacMismatch));
+                continue;
+            }
+            let (next, processed) = result.unwrap();
+            assert_eq!(next, expected[i]);
+            match processed {
+                Processed::Forward { data: data, .. } => {
+                    assert_eq!(data, GenericArray::generate(|_| i as u8))
+                },
+                Processed::Exit { .. } => panic!("the node is not the exit"),
+            }
+        }
+    };
+
+    check(Packet::process_batch(packets(), &secret, &[]));
+}
+
+#[test]
+fn buffer() {
+    use super::{GlobalData, LocalData, PacketBuffer, BufferProcessed, ProcessError};
+    use generic_array::typenum::{U19, U5};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use serde::Serialize;
+    use tirse::{DefaultBinarySerializer, WriteWrapper};
+
+    type Packet = FullPacket<U19, U5, [u8; 32]>;
+    type Buffer = PacketBuffer<FullSphinx, U19, U5>;
+
+    fn bytes(packet: &Packet) -> Vec<u8> {
+        let s = DefaultBinarySerializer::<WriteWrapper<Vec<_>>, String>::new(Vec::new());
+        packet.serialize(s).unwrap().consume().into_inner()
+    }
+
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..4)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let payloads = (0..4)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let (data, mut public_key) =
+        GlobalData::new::<_, FullSphinx>(&session_key, path.into_iter()).unwrap();
+    let packet = Packet::new(data, &[1, 2], payloads.clone().into_iter(), [0x5a; 32]).unwrap();
+
+    let mut buffer = bytes(&packet);
+    assert_eq!(buffer.len(), Buffer::header_size() + 32);
+    assert_eq!(
+        Buffer::process(
+            &mut buffer[..10],
+            &[1, 2],
+            &LocalData::next::<FullSphinx>(&secrets[0], &public_key)
+                .unwrap()
+                .0
+        ),
+        Err(ProcessError::MalformedPacket)
+    );
+
+    // the buffer goes through the same states as the owned packet
+    let mut packet = Some(packet);
+    for (i, secret) in secrets.iter().enumerate() {
+        let (local, next) = LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+        assert!(!Buffer::verify(&buffer, &[1, 3], &local));
+        let processed = Buffer::process(&mut buffer, &[1, 2], &local).unwrap();
+        match packet.take().unwrap().process(&[1, 2], &local).unwrap() {
+            Processed::Forward {
+                data: data,
+                next: next,
+            } => {
+                assert_eq!(processed, BufferProcessed::Forward { data: data });
+                assert_eq!(buffer, bytes(&next));
+                packet = Some(next);
+            },
+            Processed::Exit {
+                data: data,
+                message: message,
+            } => {
+                assert_eq!(processed, BufferProcessed::Exit { data: data });
+                assert_eq!(&buffer[Buffer::header_size()..], &message[..]);
+                assert_eq!(message, [0x5a; 32]);
+            },
+        }
+        assert_eq!(
+            processed,
+            match i {
+                3 => BufferProcessed::Exit {
+                    data: payloads[i].clone()
+                },
+                _ => BufferProcessed::Forward {
+                    data: payloads[i].clone()
+                },
+            }
+        );
+        public_key = next;
+    }
+    assert!(packet.is_none());
+}
+
+#[test]
+fn wiping() {
+    use super::Sphinx;
+    use super::sphinx::{wipe, blank_mac, blank_stream};
+    use keystream::KeyStream;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use chacha::ChaCha;
+    use generic_array::typenum::U32;
+
+    let wiped = cfg!(feature = "zeroize");
+
+    let mut key = [0x5au8; 32];
+    wipe(key.as_mut());
+    assert_eq!(key == [0; 32], wiped);
+
+    // a wiped stream or mac is keyed by zeros
+    let shared = GenericArray::clone_from_slice(&[0x42; 32]);
+    let mut stream = FullSphinx::rho(&shared);
+    stream.wipe();
+    let mut output = [0; 64];
+    stream.xor_read(output.as_mut()).unwrap();
+    let mut blank = [0; 64];
+    blank_stream::<U32, ChaCha>()
+        .xor_read(blank.as_mut())
+        .unwrap();
+    assert_eq!(output.as_ref() == blank.as_ref(), wiped);
+
+    let mut collector = FullSphinx::mu(&shared);
+    collector.wipe();
+    let blank = blank_mac::<Hmac<Sha256>>().result().code();
+    assert_eq!(FullSphinx::output(collector) == blank, wiped);
+}
+
+#[test]
+fn constant_time_correctness() {
+    use super::{GlobalData, LocalData, VariableMessage, PacketBuffer, ProcessError};
+    use super::sphinx::{constant_time_eq, constant_time_is_zero};
+    use generic_array::typenum::{U19, U5, U400, U20};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    type Packet = FullPacket<U19, U5, [u8; 32]>;
+    type Variable = VariableMessage<FullSphinx, U400, [u8; 32]>;
+    type Buffer = PacketBuffer<FullSphinx, U19, U5>;
+
+    // only the results of the comparisons are checked, their timing is not measured
+    assert!(constant_time_eq(&[], &[]));
+    assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+    assert!(!constant_time_eq(&[1, 2, 3], &[0, 2, 3]));
+    assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 0]));
+    assert!(!constant_time_eq(&[1, 2, 3], &[1, 2]));
+    assert!(constant_time_is_zero(&[0; 32]));
+    assert!(!constant_time_is_zero(&[0, 0, 0, 0x80]));
+    assert!(!constant_time_is_zero(&[1, 0, 0, 0]));
+
+    let context = Secp256k1::new();
+    let secret = SecretKey::new(&mut rand::thread_rng());
+    let public = PublicKey::from_secret_key(&context, &secret);
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let payload = GenericArray::generate(|_| rand::random::<u8>());
+
+    // the construction is deterministic, so each closure gives the same packet again
+    let packet = || {
+        let (data, _) =
+            GlobalData::new::<_, FullSphinx>(&session_key, vec![public].into_iter()).unwrap();
+        Packet::new(data, &[1, 2], vec![payload].into_iter(), [0x5a; 32]).unwrap()
+    };
+    let variable = || {
+        let (data, _) =
+            GlobalData::<_, U20>::new::<_, FullSphinx>(&session_key, vec![public].into_iter())
+                .unwrap();
+        Variable::new(data, &[1, 2], vec![vec![1, 2, 3]].into_iter(), [0x5a; 32]).unwrap()
+    };
+    let buffer = || {
+        let packet = packet();
+        let mut buffer = Vec::new();
+        for item in packet.routing_info.as_ref() {
+            buffer.extend_from_slice(item.data.as_ref());
+            buffer.extend_from_slice(item.hmac.as_ref());
+        }
+        buffer.extend_from_slice(packet.hmac.as_ref());
+        buffer.extend_from_slice(&packet.message);
+        buffer
+    };
+    let public_key = PublicKey::from_secret_key(&context, &session_key);
+    let (local, _) = LocalData::next::<FullSphinx>(&secret, &public_key).unwrap();
+
+    // a difference in the first byte and in the last byte of the mac are rejected alike
+    let position = Buffer::header_size() - 32;
+    for &index in [0, 31].iter() {
+        let mut packet = packet();
+        packet.hmac[index] ^= 1;
+        assert!(!packet.verify(&[1, 2], &local));
+        assert_eq!(
+            packet.process(&[1, 2], &local).err(),
+            Some(ProcessError::MacMismatch)
+        );
+
+        let mut variable = variable();
+        variable.hmac[index] ^= 1;
+        assert_eq!(
+            variable.process(&[1, 2], &local).err(),
+            Some(ProcessError::MacMismatch)
+        );
+
+        let mut buffer = buffer();
+        buffer[position + index] ^= 1;
+        assert!(!Buffer::verify(&buffer, &[1, 2], &local));
+        assert_eq!(
+            Buffer::process(&mut buffer, &[1, 2], &local),
+            Err(ProcessError::MacMismatch)
+        );
+    }
+
+    // the untouched packets still reach the exit
+    let packet = packet();
+    assert!(packet.verify(&[1, 2], &local));
+    match packet.process(&[1, 2], &local).unwrap() {
+        Processed::Exit { data: data, .. } => assert_eq!(data, payload),
+        Processed::Forward { .. } => panic!("the only hop must be the exit"),
+    }
+    assert!(variable().process(&[1, 2], &local).is_ok());
+    assert!(Buffer::process(&mut buffer(), &[1, 2], &local).is_ok());
+}
+
+#[test]
+fn codec() {
+    use super::{GlobalData, LocalData, CodecError, point_to_bytes, point_from_bytes};
+    use generic_array::typenum::{U19, U5};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use serde::Serialize;
+    use tirse::{DefaultBinarySerializer, WriteWrapper};
+
+    type Packet = FullPacket<U19, U5, [u8; 32]>;
+
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..3)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let payloads = (0..3)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let (data, public_key) =
+        GlobalData::new::<_, FullSphinx>(&session_key, path.into_iter()).unwrap();
+    let packet = Packet::new(data, &[], payloads.into_iter(), [0x5a; 32]).unwrap();
+
+    // the same bytes the serde tuple gives with a plain binary serializer, after the version
+    let bytes = packet.to_bytes();
+    let s = DefaultBinarySerializer::<WriteWrapper<Vec<_>>, String>::new(Vec::new());
+    let serialized = packet.serialize(s).unwrap().consume().into_inner();
+    assert_eq!(bytes.len(), Packet::header_size() + 32);
+    assert_eq!(bytes[0], Packet::VERSION);
+    assert_eq!(&bytes[1..], &serialized[..]);
+
+    let mut buffer = vec![0; bytes.len() + 1];
+    assert_eq!(
+        packet.encode_into(&mut buffer),
+        Err(CodecError::Length {
+            expected: bytes.len(),
+            actual: bytes.len() + 1,
+        })
+    );
+    packet.encode_into(&mut buffer[1..]).unwrap();
+    assert_eq!(&buffer[1..], &bytes[..]);
+
+    let decoded = Packet::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded, packet);
+    assert_eq!(
+        Packet::from_bytes(&bytes[1..]).err(),
+        Some(CodecError::Length {
+            expected: bytes.len(),
+            actual: bytes.len() - 1,
+        })
+    );
+    let mut unknown = bytes.clone();
+    unknown[0] = 1;
+    assert_eq!(
+        Packet::from_bytes(&unknown).err(),
+        Some(CodecError::UnknownVersion(1))
+    );
+
+    let point = point_to_bytes(&public_key);
+    assert_eq!(point, public_key.serialize().to_vec());
+    assert_eq!(point_from_bytes::<PublicKey>(&point), Ok(public_key));
+    assert_eq!(
+        point_from_bytes::<PublicKey>(&[0; 33]),
+        Err(CodecError::InvalidPoint)
+    );
+    assert_eq!(
+        point_from_bytes::<PublicKey>(&point[1..]),
+        Err(CodecError::Length {
+            expected: 33,
+            actual: 32,
+        })
+    );
+
+    // the decoded packet is as good as the original
+    let (local, _) = LocalData::next::<FullSphinx>(&secrets[0], &public_key).unwrap();
+    assert!(decoded.verify(&[], &local));
+}
+
+#[test]
+fn onion() {
+    use super::{OnionPacket, OnionPacketProcessed, CodecError};
+    use generic_array::typenum::{U19, U5, U64};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use serde::Serialize;
+    use tirse::{DefaultBinarySerializer, WriteWrapper};
+    use rac::{LineValid, Curve};
+
+    type Onion = OnionPacket<FullSphinx, U19, U5, GenericArray<u8, U64>>;
+
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..4)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let payloads = (0..4)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+    let message = GenericArray::generate(|_| rand::random::<u8>());
+
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let packet = Onion::new(
+        &session_key,
+        path.into_iter(),
+        &[],
+        payloads.clone().into_iter(),
+        message.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        packet.public_key(),
+        &PublicKey::from_secret_key(&context, &session_key)
+    );
+
+    // the key and the packet the `path` test serializes by hand, behind the version
+    let bytes = packet.to_bytes();
+    let s = DefaultBinarySerializer::<WriteWrapper<Vec<_>>, String>::new(Vec::new());
+    let v = (
+        packet.public_key().compress().clone_line(),
+        packet.message(),
+    )
+        .serialize(s)
+        .unwrap()
+        .consume()
+        .into_inner();
+    assert_eq!(bytes.len(), Onion::header_size() + 64);
+    assert_eq!(bytes[0], Onion::VERSION);
+    assert_eq!(&bytes[1..], &v[..]);
+    assert_eq!(Onion::from_bytes(&bytes), Ok(packet));
+    assert_eq!(
+        Onion::from_bytes(&bytes[..1]).err(),
+        Some(CodecError::Length {
+            expected: bytes.len(),
+            actual: 1,
+        })
+    );
+    let mut invalid = bytes.clone();
+    invalid[1] = 0xff;
+    assert_eq!(
+        Onion::from_bytes(&invalid).err(),
+        Some(CodecError::InvalidPoint)
+    );
+
+    // each hop needs only its secret key and the bytes it received
+    let mut bytes = bytes;
+    let mut output = Vec::new();
+    for (i, secret) in secrets.iter().enumerate() {
+        let packet = Onion::from_bytes(&bytes).unwrap();
+        match packet.process(secret, &[]).unwrap() {
+            OnionPacketProcessed::Forward {
+                data: data,
+                next: next,
+            } => {
+                assert!(i < 3);
+                output.push(data);
+                bytes = next.to_bytes();
+            },
+            OnionPacketProcessed::Exit {
+                data: data,
+                message: m,
+            } => {
+                assert_eq!(i, 3);
+                assert_eq!(m, message);
+                output.push(data);
+            },
+        }
+    }
+    assert_eq!(output, payloads);
+
+    let packet = Onion::from_bytes(&bytes).unwrap();
+    assert_eq!(
+        packet.process(&secrets[0], &[]).err(),
+        Some(super::ProcessError::MacMismatch)
+    );
+}
+
+#[test]
+fn builder() {
+    use super::{PacketBuilder, BuildError, OnionPacket, OnionPacketProcessed, ProcessError};
+    use generic_array::typenum::{U19, U5, U64};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+
+    type Message = GenericArray<u8, U64>;
+    type Builder = PacketBuilder<FullSphinx, U19, U5, Message>;
+
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..6)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let message: Message = GenericArray::generate(|_| rand::random::<u8>());
+
+    let builder = |hops: usize| {
+        path.iter()
+            .take(hops)
+            .enumerate()
+            .fold(Builder::new(), |builder, (i, public)| {
+                builder.hop(public.clone(), [i as u8; 7])
+            })
+            .associated_data(b"associated")
+            .message(message.clone())
+    };
+
+    assert_eq!(
+        Builder::new()
+            .message(message.clone())
+            .build(&mut rand::thread_rng())
+            .err(),
+        Some(BuildError::EmptyPath)
+    );
+    assert_eq!(
+        builder(6).build(&mut rand::thread_rng()).err(),
+        Some(BuildError::PathTooLong { hops: 6, max: 5 })
+    );
+    assert_eq!(
+        builder(2)
+            .hop(path[2].clone(), [0; 20])
+            .build(&mut rand::thread_rng())
+            .err(),
+        Some(BuildError::PayloadTooLong {
+            hop: 2,
+            length: 20,
+            max: 19,
+        })
+    );
+    assert_eq!(
+        Builder::new()
+            .hop(path[0].clone(), [])
+            .build(&mut rand::thread_rng())
+            .err(),
+        Some(BuildError::MissingMessage)
+    );
+    assert_eq!(
+        PacketBuilder::<LionessSphinx, U19, U5, [u8; 8]>::new()
+            .hop(path[0].clone(), [])
+            .message([0; 8])
+            .build(&mut rand::thread_rng())
+            .err(),
+        Some(BuildError::MessageTooShort { length: 8, min: 16 })
+    );
+    assert_eq!(
+        PacketBuilder::<LionessSphinx, U19, U5, Message>::new()
+            .hop(path[0].clone(), [])
+            .message(message.clone())
+            .build(&mut rand::thread_rng())
+            .err(),
+        Some(BuildError::MessageTooLong { max: 48 })
+    );
+
+    // the same packet the low level constructor gives for the same session key
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+    let payloads = (0..5)
+        .map(|i| {
+            let mut payload = GenericArray::default();
+            payload[..7].copy_from_slice(&[i as u8; 7]);
+            payload
+        })
+        .collect::<Vec<GenericArray<u8, U19>>>();
+    let expected = OnionPacket::<FullSphinx, U19, U5, Message>::with_padding(
+        &session_key,
+        path.iter().take(5).cloned(),
+        b"associated",
+        payloads.clone().into_iter(),
+        message.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        builder(5).build_with_session_key(&session_key),
+        Ok(expected)
+    );
+
+    let mut packet = builder(5).build(&mut rand::thread_rng()).unwrap();
+    for (i, secret) in secrets.iter().take(5).enumerate() {
+        match packet.process(secret, b"associated").unwrap() {
+            OnionPacketProcessed::Forward {
+                data: data,
+                next: next,
+            } => {
+                assert_eq!(data, payloads[i]);
+                packet = next;
+            },
+            OnionPacketProcessed::Exit {
+                data: data,
+                message: m,
+            } => {
+                assert_eq!(i, 4);
+                assert_eq!(data, payloads[i]);
+                assert_eq!(m, message);
+                break;
+            },
+        }
+    }
+
+    let packet = builder(1).build(&mut rand::thread_rng()).unwrap();
+    assert_eq!(
+        packet.process(&secrets[0], b"other").err(),
+        Some(ProcessError::MacMismatch)
+    );
+
+    // the tag of the payload cipher takes the last bytes of the message
+    let message: Message =
+        GenericArray::generate(|i| if i < 48 { rand::random::<u8>() } else { 0 });
+    let mut packet = path
+        .iter()
+        .take(3)
+        .fold(
+            PacketBuilder::<LionessSphinx, U19, U5, Message>::new(),
+            |builder, public| builder.hop(public.clone(), []),
+        )
+        .message(message.clone())
+        .build(&mut rand::thread_rng())
+        .unwrap();
+    for (i, secret) in secrets.iter().take(3).enumerate() {
+        match packet.process(secret, b"").unwrap() {
+            OnionPacketProcessed::Forward {
+                data: _,
+                next: next,
+            } => packet = next,
+            OnionPacketProcessed::Exit {
+                data: _,
+                message: m,
+            } => {
+                assert_eq!(i, 2);
+                assert_eq!(m, message);
+                return;
+            },
+        }
+    }
+    panic!("the packet did not reach the exit");
+}
+
+#[test]
+fn padding() {
+    use super::{GlobalData, LocalData, PacketBuffer, BufferProcessed, Sphinx, ProcessError};
+    use super::{AuthenticatedMessage, SharedSecret, XorCipher};
+    use generic_array::typenum::{U19, U5, Unsigned};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use keystream::KeyStream;
+    use rac::LineValid;
+
+    type Packet = FullPacket<U19, U5, [u8; 32]>;
+    type Buffer = PacketBuffer<FullSphinx, U19, U5>;
+    type MacLength = <FullSphinx as Sphinx>::MacLength;
+
+    let size = 19 + MacLength::to_usize();
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..2)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rand::thread_rng());
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+    let payloads = (0..2)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+    let session_key = SecretKey::new(&mut rand::thread_rng());
+
+    let data = || GlobalData::new::<_, FullSphinx>(&session_key, path.iter().cloned()).unwrap();
+    let plain = Packet::new(data().0, &[], payloads.clone().into_iter(), [0x5a; 32]).unwrap();
+    let padded = Packet::with_padding(
+        data().0,
+        &session_key,
+        &[],
+        payloads.clone().into_iter(),
+        [0x5a; 32],
+    )
+    .unwrap();
+    assert_eq!(
+        Packet::with_padding(data().0, &session_key, &[], Vec::new().into_iter(), [0; 32]).err(),
+        Some(ProcessError::EmptyPath)
+    );
+
+    // runs the packet to the exit, and returns what the exit sees
+    // in the routing info once its own layer is removed
+    let exit_view = |packet: Packet| {
+        let mut packet = packet;
+        let mut public_key = PublicKey::from_secret_key(&context, &session_key);
+        for (i, secret) in secrets.iter().enumerate() {
+            let (local, next) = LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+            let bytes = packet.to_bytes();
+            match packet.process(&[], &local).unwrap() {
+                Processed::Forward {
+                    data: data,
+                    next: next,
+                } => {
+                    assert_eq!(data, payloads[i]);
+                    packet = next;
+                },
+                Processed::Exit {
+                    data: data,
+                    message: message,
+                } => {
+                    assert_eq!(i, 1);
+                    assert_eq!(data, payloads[i]);
+                    assert_eq!(message, [0x5a; 32]);
+
+                    let mut buffer = bytes[1..].to_vec();
+                    assert_eq!(
+                        Buffer::process(&mut buffer, &[], &local),
+                        Ok(BufferProcessed::Exit { data: data })
+                    );
+
+                    let mut routing_info = bytes[1..(1 + 5 * size)].to_vec();
+                    FullSphinx::rho(local.shared_secret())
+                        .xor_read(&mut routing_info)
+                        .unwrap();
+                    return (routing_info, local);
+                },
+            }
+            public_key = next;
+        }
+        panic!("the packet has not reached the exit")
+    };
+
+    // the plain packet ends with a zero hmac and zeros beyond the route
+    let (routing_info, _) = exit_view(plain);
+    assert!(routing_info[19..size].iter().all(|&x| x == 0));
+    assert!(routing_info[size..(4 * size)].iter().all(|&x| x == 0));
+
+    // the padded one with the exit marker and the `pad` stream
+    let (routing_info, local) = exit_view(padded);
+    let mut pad = vec![0; 3 * size];
+    FullSphinx::pad(&session_key.clone_line())
+        .xor_read(&mut pad)
+        .unwrap();
+    assert_eq!(
+        &routing_info[19..size],
+        FullSphinx::exit_marker(local.shared_secret()).as_slice()
+    );
+    assert_eq!(&routing_info[size..(4 * size)], &pad[..]);
+    assert!(routing_info[19..(4 * size)].iter().any(|&x| x != 0));
+
+    // a suite that implements only the required methods gets the default pad and exit marker
+    struct Minimal;
+
+    impl Sphinx for Minimal {
+        type KeyLength = <FullSphinx as Sphinx>::KeyLength;
+        type MacLength = MacLength;
+        type AsymmetricKey = PublicKey;
+        type Stream = <FullSphinx as Sphinx>::Stream;
+        type Collector = <FullSphinx as Sphinx>::Collector;
+        type PayloadCipher = XorCipher<Self>;
+
+        fn mu(shared: &SharedSecret<PublicKey>) -> Self::Collector {
+            FullSphinx::mu(shared)
+        }
+
+        fn chain<T>(collector: Self::Collector, data: T) -> Self::Collector
+        where
+            T: AsRef<[u8]>,
+        {
+            FullSphinx::chain(collector, data)
+        }
+
+        fn output(collector: Self::Collector) -> GenericArray<u8, MacLength> {
+            FullSphinx::output(collector)
+        }
+
+        fn rho(shared: &SharedSecret<PublicKey>) -> Self::Stream {
+            FullSphinx::rho(shared)
+        }
+
+        fn pi(shared: &SharedSecret<PublicKey>) -> Self::Stream {
+            FullSphinx::pi(shared)
+        }
+
+        fn tau(public_key: PublicKey) -> SharedSecret<PublicKey> {
+            FullSphinx::tau(public_key)
+        }
+
+        fn blinding(
+            public_key: &PublicKey,
+            shared: &SharedSecret<PublicKey>,
+        ) -> SharedSecret<PublicKey> {
+            FullSphinx::blinding(public_key, shared)
+        }
+    }
+
+    let mut packet = AuthenticatedMessage::<Minimal, U19, U5, [u8; 32]>::with_padding(
+        GlobalData::new::<_, Minimal>(&session_key, path.iter().cloned())
+            .unwrap()
+            .0,
+        &session_key,
+        &[],
+        payloads.clone().into_iter(),
+        [0x5a; 32],
+    )
+    .unwrap();
+    let mut public_key = PublicKey::from_secret_key(&context, &session_key);
+    for (i, secret) in secrets.iter().enumerate() {
+        let (local, next) = LocalData::next::<Minimal>(secret, &public_key).unwrap();
+        match packet.process(&[], &local).unwrap() {
+            Processed::Forward {
+                data: data,
+                next: next,
+            } => {
+                assert_eq!(data, payloads[i]);
+                packet = next;
+            },
+            Processed::Exit {
+                data: data,
+                message: message,
+            } => {
+                assert_eq!(i, 1);
+                assert_eq!(data, payloads[i]);
+                assert_eq!(message, [0x5a; 32]);
+                return;
+            },
+        }
+        public_key = next;
+    }
+    panic!("the packet has not reached the exit")
+}
+
+// pearson's statistics the `path_length` test needs, the critical values are
+// for a one sided p of 1e-6, so a failure is never a fluke of the sample
+mod statistics {
+    fn counts(bytes: &[u8]) -> [f64; 256] {
+        let mut counts = [0.0; 256];
+        bytes.iter().for_each(|&x| counts[x as usize] += 1.0);
+        counts
+    }
+
+    // the byte frequencies against the uniform distribution, 255 degrees of freedom
+    pub fn uniform(bytes: &[u8]) -> f64 {
+        let expected = bytes.len() as f64 / 256.0;
+        counts(bytes)
+            .iter()
+            .map(|&observed| (observed - expected) * (observed - expected) / expected)
+            .sum()
+    }
+
+    // whether the groups share one byte distribution, whatever it is,
+    // (groups - 1) * 255 degrees of freedom
+    pub fn homogeneity(groups: &[Vec<u8>]) -> f64 {
+        let rows = groups.iter().map(|group| counts(group)).collect::<Vec<_>>();
+        let total = groups.iter().map(|group| group.len() as f64).sum::<f64>();
+        let mut columns = [0.0; 256];
+        for row in &rows {
+            for (column, observed) in columns.iter_mut().zip(row.iter()) {
+                *column += observed;
+            }
+        }
+
+        rows.iter()
+            .zip(groups.iter())
+            .map(|(row, group)| {
+                let length = group.len() as f64;
+                row.iter()
+                    .zip(columns.iter())
+                    .filter(|&(_, &column)| column > 0.0)
+                    .map(|(&observed, &column)| {
+                        let expected = length * column / total;
+                        (observed - expected) * (observed - expected) / expected
+                    })
+                    .sum::<f64>()
+            })
+            .sum()
+    }
+
+    // the wilson-hilferty approximation of the chi-square quantile
+    pub fn critical(degrees: usize) -> f64 {
+        let z = 4.753;
+        let k = degrees as f64;
+        let a = 2.0 / (9.0 * k);
+        k * (1.0 - a + z * a.sqrt()).powi(3)
+    }
+}
+
+// samples packets of every route length and looks at them at every hop,
+// what anyone on the wire sees is the routing info after the first item and the message,
+// what the exit sees is its own hmac and everything after it once its layer is removed,
+// none of it may depend on the position or on the length of the route,
+// the plain construction leaks the length to the exit, which shows the test can see a leak
+#[test]
+fn path_length() {
+    use self::statistics::{uniform, homogeneity, critical};
+    use super::{LocalData, OnionPacket, OnionPacketProcessed, PacketBuilder, Sphinx};
+    use generic_array::typenum::{U19, U5, U256, Unsigned};
+    use generic_array::sequence::GenericSequence;
+    use secp256k1::Secp256k1;
+    use keystream::KeyStream;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    type Onion = OnionPacket<FullSphinx, U19, U5, GenericArray<u8, U256>>;
+    type MacLength = <FullSphinx as Sphinx>::MacLength;
+
+    // the tails and the messages at each hop, and the view of the exit
+    type Views = (Vec<Vec<u8>>, Vec<Vec<u8>>, Vec<u8>);
+    // the same, grouped by (length, position) for the tails and the messages, by length for the exit
+    type Groups = (Vec<Vec<u8>>, Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+    const SAMPLES: usize = 8;
+
+    fn walk(packet: Onion, secrets: &[SecretKey]) -> Views {
+        let size = 19 + MacLength::to_usize();
+        let start = Onion::header_size() - 5 * size - MacLength::to_usize();
+        let end = start + 5 * size;
+
+        let mut packet = packet;
+        let (mut tails, mut messages) = (Vec::new(), Vec::new());
+        for secret in secrets {
+            let bytes = packet.to_bytes();
+            tails.push(bytes[(start + size)..end].to_vec());
+            messages.push(bytes[(end + MacLength::to_usize())..].to_vec());
+
+            let public_key = packet.public_key().clone();
+            match packet.process(secret, &[]).unwrap() {
+                OnionPacketProcessed::Forward { next: next, .. } => packet = next,
+                OnionPacketProcessed::Exit { .. } => {
+                    let (local, _) = LocalData::next::<FullSphinx>(secret, &public_key).unwrap();
+                    let mut routing_info = bytes[start..end].to_vec();
+                    FullSphinx::rho(local.shared_secret())
+                        .xor_read(&mut routing_info)
+                        .unwrap();
+                    return (tails, messages, routing_info[19..].to_vec());
+                },
+            }
+        }
+        panic!("the packet has not reached the exit")
+    }
+
+    fn sample(secrets: &[SecretKey], path: &[PublicKey], padded: bool, rng: &mut StdRng) -> Groups {
+        let (mut tails, mut messages, mut exits) = (Vec::new(), Vec::new(), Vec::new());
+        for length in 1..=5 {
+            let mut group: Views = (
+                vec![Vec::new(); length],
+                vec![Vec::new(); length],
+                Vec::new(),
+            );
+            for _ in 0..SAMPLES {
+                // the payloads and the message are constant, the worst case for a leak
+                let packet = if padded {
+                    (0..length)
+                        .fold(PacketBuilder::new(), |builder, i| {
+                            builder.hop(path[i].clone(), [i as u8; 19])
+                        })
+                        .message(GenericArray::default())
+                        .build(rng)
+                        .unwrap()
+                } else {
+                    Onion::new(
+                        &SecretKey::new(rng),
+                        path[..length].iter().cloned(),
+                        &[],
+                        (0..length).map(|i| GenericArray::generate(|_| i as u8)),
+                        GenericArray::default(),
+                    )
+                    .unwrap()
+                };
+                let (t, m, e) = walk(packet, &secrets[..length]);
+                for position in 0..length {
+                    group.0[position].extend_from_slice(&t[position]);
+                    group.1[position].extend_from_slice(&m[position]);
+                }
+                group.2.extend_from_slice(&e);
+            }
+            tails.extend(group.0);
+            messages.extend(group.1);
+            exits.push(group.2);
+        }
+        (tails, messages, exits)
+    }
+
+    // the seed is fixed, so the statistics are the same on every run
+    let mut rng = StdRng::seed_from_u64(25);
+    let context = Secp256k1::new();
+    let (secrets, path): (Vec<SecretKey>, Vec<PublicKey>) = (0..5)
+        .map(|_| {
+            let secret = SecretKey::new(&mut rng);
+            let public = PublicKey::from_secret_key(&context, &secret);
+            (secret, public)
+        })
+        .unzip();
+
+    let (tails, messages, exits) = sample(&secrets, &path, true, &mut rng);
+    assert_eq!(tails.len(), 15);
+    for group in tails.iter().chain(messages.iter()).chain(exits.iter()) {
+        assert!(uniform(group) < critical(255));
+    }
+    assert!(homogeneity(&tails) < critical(14 * 255));
+    assert!(homogeneity(&messages) < critical(14 * 255));
+    assert!(homogeneity(&exits) < critical(4 * 255));
+
+    // on the wire the plain packet looks the same, but the exit sees the zero hmac
+    // and zeros beyond the route, so it learns the length of the route
+    let (tails, messages, exits) = sample(&secrets, &path, false, &mut rng);
+    for group in tails.iter().chain(messages.iter()) {
+        assert!(uniform(group) < critical(255));
+    }
+    assert!(homogeneity(&tails) < critical(14 * 255));
+    assert!(homogeneity(&messages) < critical(14 * 255));
+    for exit in &exits {
+        assert!(uniform(exit) > critical(255));
+    }
+    assert!(homogeneity(&exits) > critical(4 * 255));
+}
+
+#[cfg(feature = "x25519")]
+#[test]
+fn x25519() {
+    use super::{
+        GlobalData, LocalData, AuthenticatedMessage, X25519Scalar, X25519Point, CodecError,
+        ProcessError, point_from_bytes,
+    };
+    use generic_array::typenum::{U19, U5};
+    use generic_array::sequence::GenericSequence;
+    use either::{Left, Right};
+    use sha2::Sha256;
+    use chacha::ChaCha;
+    use hmac::Hmac;
+    use rac::{LineValid, Curve};
+
+    type X25519Sphinx = (X25519Point, Hmac<Sha256>, Sha256, ChaCha);
+    type X25519Packet<L, N, P> = AuthenticatedMessage<X25519Sphinx, L, N, P>;
+
+    let (secrets, path): (Vec<X25519Scalar>, Vec<X25519Point>) = (0..4)
+        .map(|_| {
+            let secret = X25519Scalar::clamp(rand::random());
+            let public = X25519Point::from_secret(&secret);
+            (secret, public)
+        })
+        .unzip();
+
+    // the point is its own compressed form, a key reads back as itself
+    let public = path[0].clone_line();
+    assert_eq!(
+        X25519Point::try_clone_array(&public).unwrap().compress(),
+        path[0]
+    );
+    let secret = secrets[0].clone_line();
+    assert_eq!(
+        X25519Scalar::try_clone_array(&secret),
+        Ok(secrets[0].clone())
+    );
+
+    // RFC 7748 vectors
+    let x25519 = |scalar: &str, u: &str| {
+        let scalar =
+            X25519Scalar::try_clone_array(GenericArray::from_slice(&hex::decode(scalar).unwrap()))
+                .unwrap();
+        let point =
+            X25519Point::try_clone_array(GenericArray::from_slice(&hex::decode(u).unwrap()))
+                .unwrap();
+        hex::encode(point.exp_ec(&scalar).clone_line())
+    };
+    assert_eq!(
+        x25519(
+            "a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4",
+            "e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c",
+        ),
+        "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"
+    );
+    let nine = "0900000000000000000000000000000000000000000000000000000000000000";
+    let (mut k, mut u) = (nine.to_string(), nine.to_string());
+    for i in 1..=1000 {
+        let next = x25519(&k, &u);
+        u = k;
+        k = next;
+        if i == 1 {
+            assert_eq!(
+                k,
+                "422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079"
+            );
+        }
+    }
+    assert_eq!(
+        k,
+        "684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51"
+    );
+    let alice = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+    let alice_public = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+    let bob = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+    let bob_public = "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f";
+    let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+    assert_eq!(x25519(alice, nine), alice_public);
+    assert_eq!(x25519(bob, nine), bob_public);
+    assert_eq!(x25519(alice, bob_public), shared);
+    assert_eq!(x25519(bob, alice_public), shared);
+
+    // the points of small order, the non canonical encodings included
+    for &u in [
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "e0eb7a7c3b41b8ae1656e3faf19fc46ada098deb9c32b1fd866205165f49b800",
+        "5f9c95bca3508c24b1d0b1559c83ef5b04445cc4581c8e86d8224eddd09f1157",
+        "ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        "edffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        "eeffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+    ]
+    .iter()
+    {
+        assert_eq!(
+            point_from_bytes::<X25519Point>(&hex::decode(u).unwrap()),
+            Err(CodecError::InvalidPoint)
+        );
+    }
+
+    // points can not be added, the sum is the identity, no key exchange accepts it
+    let sum = path[0].mul_ec(&path[1]);
+    assert_eq!(X25519Point::try_clone_array(&sum.clone_line()), Err(()));
+    assert_eq!(
+        LocalData::next::<X25519Sphinx>(&secrets[0], &sum).err(),
+        Some(ProcessError::InvalidPoint)
+    );
+
+    let payloads = (0..4)
+        .map(|_| GenericArray::generate(|_| rand::random::<u8>()))
+        .collect::<Vec<_>>();
+
+    let message = [0x5au8; 64];
+
+    let secret = X25519Scalar::clamp(rand::random());
+    let (data, public_key) = GlobalData::new::<_, X25519Sphinx>(&secret, path.into_iter()).unwrap();
+    assert_eq!(public_key, X25519Point::from_secret(&secret));
+    let packet =
+        X25519Packet::<U19, U5, _>::new(data, b"associated", payloads.clone().into_iter(), message)
+            .unwrap();
+
+    let initial = (Left(packet), Vec::new(), public_key);
+    let (last, output, _) =
+        secrets
+            .into_iter()
+            .fold(initial, |(packet, mut payloads, public_key), secret| {
+                let packet = packet.left().unwrap();
+                let (local, public_key) =
+                    LocalData::next::<X25519Sphinx>(&secret, &public_key).unwrap();
+                match packet.process(b"associated", &local).unwrap() {
+                    Processed::Forward {
+                        data: data,
+                        next: next,
+                    } => {
+                        payloads.push(data);
+                        (Left(next), payloads, public_key)
+                    },
+                    Processed::Exit {
+                        data: data,
+                        message: message,
+                    } => {
+                        payloads.push(data);
+                        (Right(message), payloads, public_key)
+                    },
+                }
+            });
+
+    assert_eq!(payloads, output);
+    assert_eq!(last.right(), Some(message));
+}
+
+#[cfg(feature = "ristretto")]
+#[test]
+fn ristretto() {
+    use super::{
+        GlobalData, LocalData, VariableMessage, VariableProcessed, ProcessError, Sphinx,
+        Ristretto255Scalar, Ristretto255Point,
+    };
+    use generic_array::typenum::{U400, U20};
+    use sha2::Sha256;
+    use chacha::ChaCha;
+    use hmac::Hmac;
+    use rac::{LineValid, Curve};
+
+    type RistrettoSphinx = (Ristretto255Point, Hmac<Sha256>, Sha256, ChaCha);
+
+    let random_scalar = || {
+        let bytes = GenericArray::clone_from_slice(&rand::random::<[u8; 32]>());
+        Ristretto255Scalar::try_clone_array(&bytes).unwrap()
+    };
+
+    // RFC 9496 multiples of the generator
+    let multiples = [
+        "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76",
+        "6a493210f7499cd17fecb510ae0cea23a110e8d5b901f8acadd3095c73a3b919",
+        "94741f5d5d52755ece4f23f044ee27d5d1ea1e2bd196b462166b16152a9d0259",
+        "da80862773358b466ffadfe0b3293ab3d9fd53c5ea6c955358f568322daf6a57",
+        "e882b131016b52c1d3337080187cf768423efccbb517bb495ab812c4160ff44e",
+    ];
+    let mut sum = Ristretto255Point::base();
+    for (i, &multiple) in multiples.iter().enumerate() {
+        let mut bytes = GenericArray::default();
+        bytes[0] = i as u8 + 1;
+        let scalar = Ristretto255Scalar::try_clone_array(&bytes).unwrap();
+        assert_eq!(
+            hex::encode(Ristretto255Point::from_secret(&scalar).clone_line()),
+            multiple
+        );
+        assert_eq!(hex::encode(sum.clone_line()), multiple);
+        sum = sum.mul_ec(&Ristretto255Point::base());
+    }
+
+    // RFC 9496 bad encodings, a negative and two non canonical field elements
+    for &bad in [
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "edffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        "f3ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+    ]
+    .iter()
+    {
+        let bytes = GenericArray::clone_from_slice(&hex::decode(bad).unwrap());
+        assert!(Ristretto255Point::try_clone_array(&bytes).is_err());
+    }
+
+    // the high bit is never set in a canonical encoding
+    let mut bytes = Ristretto255Point::base().clone_line();
+    bytes[31] |= 0x80;
+    assert!(Ristretto255Point::try_clone_array(&bytes).is_err());
+    assert!(Ristretto255Scalar::try_clone_array(&GenericArray::default()).is_err());
+
+    // the identity is a canonical encoding, but no secret can be derived from it
+    let identity = Ristretto255Point::try_clone_array(&GenericArray::default()).unwrap();
+    assert_eq!(
+        LocalData::next::<RistrettoSphinx>(&random_scalar(), &identity).err(),
+        Some(ProcessError::InvalidPoint)
+    );
+
+    for length in 1..=5 {
+        let (secrets, path): (Vec<Ristretto255Scalar>, Vec<Ristretto255Point>) = (0..length)
+            .map(|_| {
+                let secret = random_scalar();
+                let public = Ristretto255Point::from_secret(&secret);
+                (secret, public)
+            })
+            .unzip();
+        let payloads = (0..length)
+            .map(|i| vec![i as u8; 1 + i])
+            .collect::<Vec<_>>();
+
+        let session_key = random_scalar();
+        let (data, public_key) =
+            GlobalData::<_, U20>::new::<_, RistrettoSphinx>(&session_key, path.into_iter())
+                .unwrap();
+        let shared_secrets = data.shared_secrets.clone();
+        let packet = VariableMessage::<RistrettoSphinx, U400, _>::new(
+            data,
+            b"associated",
+            payloads.clone().into_iter(),
+            [0x5au8; 16],
+        )
+        .unwrap();
+
+        let mut packet = Some(packet);
+        let mut public_key = public_key;
+        let mut output = Vec::new();
+        for secret in secrets.iter() {
+            // the ephemeral key and the blinding factor survive the wire unchanged
+            let line = public_key.clone_line();
+            assert_eq!(Ristretto255Point::try_clone_array(&line), Ok(public_key));
+            assert_eq!(
+                Ristretto255Point::try_clone_array(&line)
+                    .unwrap()
+                    .clone_line(),
+                line
+            );
+
+            // the relay derives the secret the sender derived for it
+            let (local, next) = LocalData::next::<RistrettoSphinx>(secret, &public_key).unwrap();
+            assert_eq!(local.shared_secret, shared_secrets[output.len()]);
+            let blinding = RistrettoSphinx::blinding(&public_key, &local.shared_secret);
+            let blinding = Ristretto255Scalar::try_clone_array(&blinding).unwrap();
+            assert_eq!(
+                Ristretto255Scalar::try_clone_array(&blinding.clone_line()),
+                Ok(blinding.clone())
+            );
+            assert_eq!(next, public_key.exp_ec(&blinding));
+
+            match packet
+                .take()
+                .unwrap()
+                .process(b"associated", &local)
+                .unwrap()
+            {
+                VariableProcessed::Forward {
+                    data: data,
+                    next: next,
+                } => {
+                    output.push(data);
+                    packet = Some(next);
+                },
+                VariableProcessed::Exit {
+                    data: data,
+                    message: message,
+                } => {
+                    output.push(data);
+                    assert_eq!(message, [0x5au8; 16]);
+                },
+            }
+            public_key = next;
+        }
+        assert!(packet.is_none());
+        assert_eq!(output, payloads);
+    }
+}
+
+#[cfg(any(
+    feature = "suite-secp256k1",
+    feature = "suite-x25519-blake2b",
+    feature = "suite-x25519-aes"
+))]
+#[test]
+fn suites() {
+    use super::Sphinx;
+    use keystream::KeyStream;
+    use rac::Curve;
+
+    // mac of `abc` under `mu` and the first 32 bytes of `rho`, the shared secret is all 0x42,
+    // regression values computed by this crate, there are no published vectors for them
+    fn keys<B>(mu: &str, rho: &str)
+    where
+        B: Sphinx,
+    {
+        let shared = GenericArray::clone_from_slice(&[0x42; 32]);
+        let mut stream = [0; 32];
+        B::rho(&shared).xor_read(stream.as_mut()).unwrap();
+        assert_eq!(hex::encode(B::output(B::chain(B::mu(&shared), b"abc"))), mu);
+        assert_eq!(hex::encode(stream), rho);
+    }
+
+    #[cfg(feature = "suite-secp256k1")]
+    {
+        use super::Secp256k1Sha256ChaCha20;
+
+        // the first hop of the BOLT #4 key derivation vector
+        let session_key = SecretKey::from_slice(&[0x41; 32]).unwrap();
+        let node_id =
+            hex::decode("02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619")
+                .unwrap();
+        let node_id = PublicKey::from_slice(node_id.as_ref()).unwrap();
+        assert_eq!(
+            hex::encode(Secp256k1Sha256ChaCha20::tau(node_id.exp_ec(&session_key))),
+            "53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66"
+        );
+
+        keys::<Secp256k1Sha256ChaCha20>(
+            "12fe4fe45f2d7acb962e9e48fc714575f089905a64f1f83bee29308f905ca495",
+            "153e612c85845c8604a31ed9bfb9c0afcaf6bbf12f34f5456b13b35f647c1e33",
+        );
+
+        // the payload stream and the hmac of the failure message have their own keys
+        {
+            use super::ErrorKeys;
+            use hmac::{Hmac, Mac};
+            use sha2::Sha256;
+            use chacha::ChaCha;
+
+            let shared = GenericArray::clone_from_slice(&[0x42; 32]);
+            let key = |label: &[u8]| {
+                let mut collector = Hmac::<Sha256>::new_varkey(label).unwrap();
+                collector.input(&shared);
+                let mut key = [0; 32];
+                key.copy_from_slice(&collector.result().code());
+                key
+            };
+
+            let mut expected = [0; 32];
+            ChaCha::new_chacha20(&key(b"pi"), &[0; 8])
+                .xor_read(expected.as_mut())
+                .unwrap();
+            let mut stream = [0; 32];
+            Secp256k1Sha256ChaCha20::pi(&shared)
+                .xor_read(stream.as_mut())
+                .unwrap();
+            assert_eq!(stream, expected);
+
+            let mut collector = Hmac::<Sha256>::new_varkey(&key(b"um")).unwrap();
+            collector.input(b"abc");
+            assert_eq!(
+                Secp256k1Sha256ChaCha20::output(Secp256k1Sha256ChaCha20::chain(
+                    Secp256k1Sha256ChaCha20::um(&shared),
+                    b"abc"
+                )),
+                collector.result().code()
+            );
+        }
+    }
+
+    #[cfg(any(feature = "suite-x25519-blake2b", feature = "suite-x25519-aes"))]
+    fn round_trip<B>()
+    where
+        B: Sphinx<AsymmetricKey = super::X25519Point>,
+    {
+        use super::{GlobalData, LocalData, VariableMessage, VariableProcessed, X25519Scalar};
+        use super::X25519Point;
+        use generic_array::typenum::{U400, U20};
+
+        let (secrets, path): (Vec<X25519Scalar>, Vec<X25519Point>) = (0..3)
+            .map(|_| {
+                let secret = X25519Scalar::clamp(rand::random());
+                let public = X25519Point::from_secret(&secret);
+                (secret, public)
+            })
+            .unzip();
+        let payloads = (0..3u8)
+            .map(|i| vec![i; 5 + i as usize])
+            .collect::<Vec<_>>();
+
+        let session_key = X25519Scalar::clamp(rand::random());
+        let (data, mut public_key) =
+            GlobalData::<_, U20>::new::<_, B>(&session_key, path.into_iter()).unwrap();
+        let mut packet = Some(
+            VariableMessage::<B, U400, _>::new(data, &[], payloads.clone().into_iter(), [0u8; 8])
+                .unwrap(),
+        );
+        for (i, secret) in secrets.iter().enumerate() {
+            let (local, next) = LocalData::next::<B>(secret, &public_key).unwrap();
+            let data = match packet.take().unwrap().process(&[], &local).unwrap() {
+                VariableProcessed::Forward {
+                    data: data,
+                    next: next,
+                } => {
+                    packet = Some(next);
+                    data
+                },
+                VariableProcessed::Exit { data: data, .. } => data,
+            };
+            assert_eq!(data, payloads[i]);
+            public_key = next;
+        }
+        assert!(packet.is_none());
+    }
+
+    // RFC 7748 section 6.1
+    #[cfg(any(feature = "suite-x25519-blake2b", feature = "suite-x25519-aes"))]
+    let shared_point = {
+        use super::{X25519Scalar, X25519Point};
+        use rac::LineValid;
+
+        let mut alice = [0; 32];
+        alice.copy_from_slice(
+            hex::decode("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+                .unwrap()
+                .as_ref(),
+        );
+        let alice = X25519Scalar::clamp(alice);
+        assert_eq!(
+            hex::encode(X25519Point::from_secret(&alice).clone_line()),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+        let bob = hex::decode("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+            .unwrap();
+        let bob = X25519Point::try_clone_array(GenericArray::from_slice(bob.as_ref())).unwrap();
+        let shared_point = bob.exp_ec(&alice);
+        assert_eq!(
+            hex::encode(shared_point.clone_line()),
+            "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742"
+        );
+        shared_point
+    };
+
+    // RFC 8439 keystream of the zero key and the zero nonce
+    #[cfg(any(feature = "suite-secp256k1", feature = "suite-x25519-blake2b"))]
+    {
+        use super::PseudoRandomStream;
+        use chacha::ChaCha;
+        use generic_array::typenum::U32;
+
+        let mut stream = [0; 64];
+        <ChaCha as PseudoRandomStream<U32>>::seed(GenericArray::default())
+            .xor_read(stream.as_mut())
+            .unwrap();
+        assert_eq!(
+            hex::encode(stream.as_ref()),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+        );
+    }
+
+    #[cfg(feature = "suite-x25519-blake2b")]
+    {
+        use super::{X25519Blake2bChaCha20, Blake2b256};
+        use digest::{Input, FixedOutput};
+        use crypto_mac::Mac;
+        use blake2::Blake2b;
+
+        assert_eq!(
+            hex::encode(Blake2b256::default().chain(b"abc").fixed_result()),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+        // the first keyed entry of the blake2b known answers, the key is 0, 1, .. 63
+        let key = (0..64).collect::<Vec<u8>>();
+        assert_eq!(
+            hex::encode(Blake2b::new_varkey(key.as_ref()).unwrap().result().code()),
+            "10ebb67700b1868efb4417987acf4690ae9d972fb7a590c2f02871799aaa4786\
+             b5e996e8f0f4eb981fc214b005f42d2ff4233499391653df7aefcbc13fc51568"
+        );
+
+        // regression value
+        assert_eq!(
+            hex::encode(X25519Blake2bChaCha20::tau(shared_point)),
+            "bb16f461d45d47c32a89c90de36d7c7902b314364611d6e4a58247162ec9d4d9"
+        );
+
+        keys::<X25519Blake2bChaCha20>(
+            "12542b959e986f7f4d62eb6dc6265c4f41c76ad0884fabb5888efe842964a024\
+             a78eaa47b6fca87e4613363c4b0d9bfaa6e08bcf1c4241e15a547dc1c8edc8c4",
+            "37af886e30f2d3f4441a37759592613cd2ae8e9b62018ce5274108d02d0dae8c",
+        );
+        round_trip::<X25519Blake2bChaCha20>();
+    }
+
+    #[cfg(feature = "suite-x25519-aes")]
+    {
+        use super::{X25519Sha512256Aes256Ctr, PseudoRandomStream, Aes256CtrStream};
+
+        // aes-256 of the zero block under the zero key
+        let mut stream = [0; 16];
+        Aes256CtrStream::seed(GenericArray::default())
+            .xor_read(stream.as_mut())
+            .unwrap();
+        assert_eq!(
+            hex::encode(stream.as_ref()),
+            "dc95c078a2408989ad48a21492842087"
+        );
+
+        // regression value
+        assert_eq!(
+            hex::encode(X25519Sha512256Aes256Ctr::tau(shared_point)),
+            "3b746d5a515765a7d416a68783769356d115aaaec2559f8bcf806dc867e6173a"
+        );
+
+        keys::<X25519Sha512256Aes256Ctr>(
+            "039d64c61c467401aa4dcb6a55a145f60225205c6f6f1b3c77cd5837c43eef13",
+            "b0a3e4299f9d2662ae04c1581a5124fb237cd51dcc9260fc1c1729b17be598ba",
+        );
+        round_trip::<X25519Sha512256Aes256Ctr>();
+    }
+}
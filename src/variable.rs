@@ -0,0 +1,402 @@
+use super::sphinx::{Sphinx, SharedSecret, PayloadCipher, constant_time_eq, constant_time_is_zero};
+use super::packet::{GlobalData, LocalData, ProcessError, prepend_tag, strip_tag};
+
+use generic_array::{GenericArray, ArrayLength, typenum::Unsigned};
+use keystream::{KeyStream, SeekableKeyStream};
+use alloc::{vec, vec::Vec};
+
+pub fn bigsize_length(value: u64) -> usize {
+    match value {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+pub fn write_bigsize(value: u64, output: &mut Vec<u8>) {
+    match bigsize_length(value) {
+        1 => output.push(value as u8),
+        3 => {
+            output.push(0xfd);
+            output.extend_from_slice(&(value as u16).to_be_bytes());
+        },
+        5 => {
+            output.push(0xfe);
+            output.extend_from_slice(&(value as u32).to_be_bytes());
+        },
+        _ => {
+            output.push(0xff);
+            output.extend_from_slice(&value.to_be_bytes());
+        },
+    }
+}
+
+// returns the value and the number of bytes consumed, rejects non canonical encoding
+pub fn read_bigsize(input: &[u8]) -> Result<(u64, usize), ProcessError> {
+    let read = |length: usize| -> Result<u64, ProcessError> {
+        if input.len() < 1 + length {
+            return Err(ProcessError::MalformedHopData);
+        }
+        Ok(input[1..(1 + length)]
+            .iter()
+            .fold(0, |a, &x| (a << 8) | (x as u64)))
+    };
+
+    let (value, length) = match input.first() {
+        None => return Err(ProcessError::MalformedHopData),
+        Some(&x) if x < 0xfd => (x as u64, 1),
+        Some(&0xfd) => (read(2)?, 3),
+        Some(&0xfe) => (read(4)?, 5),
+        Some(_) => (read(8)?, 9),
+    };
+
+    if bigsize_length(value) != length {
+        Err(ProcessError::MalformedHopData)
+    } else {
+        Ok((value, length))
+    }
+}
+
+pub enum VariableProcessed<B, R, P>
+where
+    B: Sphinx,
+    R: ArrayLength<u8>,
+    P: AsMut<[u8]>,
+{
+    Forward {
+        data: Vec<u8>,
+        next: VariableMessage<B, R, P>,
+    },
+    Exit {
+        data: Vec<u8>,
+        message: P,
+    },
+}
+
+// the routing info is a flat buffer of `R` bytes, each hop takes
+// `bigsize(length) || data || hmac` of it
+pub struct VariableMessage<B, R, P>
+where
+    B: Sphinx,
+    R: ArrayLength<u8>,
+    P: AsMut<[u8]>,
+{
+    pub(crate) routing_info: GenericArray<u8, R>,
+    pub(crate) hmac: GenericArray<u8, B::MacLength>,
+    pub(crate) message: P,
+}
+
+impl<B, R, P> VariableMessage<B, R, P>
+where
+    B: Sphinx,
+    R: ArrayLength<u8>,
+    P: AsMut<[u8]>,
+{
+    pub fn size() -> usize {
+        R::to_usize()
+    }
+
+    fn hop_size(data: &[u8]) -> usize {
+        bigsize_length(data.len() as u64) + data.len() + B::MacLength::to_usize()
+    }
+
+    fn hmac<T>(
+        shared_secret: &SharedSecret<B::AsymmetricKey>,
+        routing_info: &[u8],
+        associated_data: T,
+    ) -> GenericArray<u8, B::MacLength>
+    where
+        T: AsRef<[u8]>,
+    {
+        let mu = B::chain(B::mu(shared_secret), routing_info);
+        B::output(B::chain(mu, associated_data.as_ref()))
+    }
+
+    pub fn new<N, T, H>(
+        data: GlobalData<B::AsymmetricKey, N>,
+        associated_data: T,
+        payloads: H,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+        T: AsRef<[u8]>,
+        H: Iterator<Item = Vec<u8>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        Self::with_padding(
+            data,
+            GenericArray::default(),
+            associated_data,
+            payloads,
+            message,
+        )
+    }
+
+    // the routing info starts as `padding` rather than zeros,
+    // the unused tail of the buffer which the last hop sees is made of it
+    pub fn with_padding<N, T, H>(
+        data: GlobalData<B::AsymmetricKey, N>,
+        padding: GenericArray<u8, R>,
+        associated_data: T,
+        payloads: H,
+        message: P,
+    ) -> Result<Self, ProcessError>
+    where
+        N: ArrayLength<SharedSecret<B::AsymmetricKey>>,
+        T: AsRef<[u8]>,
+        H: Iterator<Item = Vec<u8>> + DoubleEndedIterator + ExactSizeIterator,
+    {
+        let shared_secrets = &data.shared_secrets;
+        let payloads = payloads.collect::<Vec<_>>();
+        let length = payloads.len();
+        if length > N::to_usize() {
+            return Err(ProcessError::PathTooLong);
+        }
+        let sizes = payloads
+            .iter()
+            .map(|p| Self::hop_size(p.as_ref()))
+            .collect::<Vec<_>>();
+        if sizes.iter().sum::<usize>() > Self::size() {
+            return Err(ProcessError::PathTooLong);
+        }
+
+        // the part of the routing info that every hop but the last one
+        // shifts in beyond the end of the buffer
+        let filler_length = sizes.iter().take(length.saturating_sub(1)).sum::<usize>();
+        let mut filler = vec![0; filler_length];
+        for i in 0..length.saturating_sub(1) {
+            let start = Self::size() - sizes[..i].iter().sum::<usize>();
+            let end = Self::size() + sizes[i];
+            let mut stream = B::rho(&shared_secrets[i]);
+            stream.seek_to(start as _)?;
+            stream.xor_read(&mut filler[..(end - start)])?;
+        }
+
+        let mut routing_info = padding;
+        let mut hmac = GenericArray::<u8, B::MacLength>::default();
+        for i in (0..length).rev() {
+            let shift = sizes[i];
+            routing_info.copy_within(0..(Self::size() - shift), shift);
+            let mut hop = Vec::with_capacity(shift);
+            write_bigsize(payloads[i].len() as u64, &mut hop);
+            hop.extend_from_slice(payloads[i].as_ref());
+            hop.extend_from_slice(hmac.as_ref());
+            routing_info[..shift].copy_from_slice(hop.as_ref());
+
+            let mut stream = B::rho(&shared_secrets[i]);
+            stream.xor_read(routing_info.as_mut())?;
+            if i == length - 1 {
+                routing_info[(Self::size() - filler_length)..].copy_from_slice(filler.as_ref());
+            }
+
+            hmac = Self::hmac(&shared_secrets[i], routing_info.as_ref(), &associated_data);
+        }
+
+        let mut message = message;
+        prepend_tag::<B>(message.as_mut())?;
+        for index in (0..length).rev() {
+            B::PayloadCipher::encrypt(&shared_secrets[index], message.as_mut())?;
+        }
+
+        Ok(VariableMessage {
+            routing_info: routing_info,
+            hmac: hmac,
+            message: message,
+        })
+    }
+
+    pub fn process<T>(
+        self,
+        associated_data: T,
+        local: &LocalData<B::AsymmetricKey>,
+    ) -> Result<VariableProcessed<B, R, P>, ProcessError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let (routing_info, hmac_received, mut message) =
+            (self.routing_info, self.hmac, self.message);
+
+        let hmac = Self::hmac(&local.shared_secret, routing_info.as_ref(), associated_data);
+        if !constant_time_eq(hmac_received.as_ref(), hmac.as_ref()) {
+            return Err(ProcessError::MacMismatch);
+        }
+
+        let mut buffer = vec![0; Self::size() * 2];
+        buffer[..Self::size()].copy_from_slice(routing_info.as_ref());
+        let mut stream = B::rho(&local.shared_secret);
+        stream.xor_read(buffer.as_mut())?;
+
+        let (length, prefix) = read_bigsize(buffer.as_ref())?;
+        let mac_length = B::MacLength::to_usize();
+        // a buffer that can not hold even an empty hop is not a packet of this shape
+        if prefix + mac_length > Self::size() {
+            return Err(ProcessError::MalformedPacket);
+        }
+        if length > (Self::size() - prefix - mac_length) as u64 {
+            return Err(ProcessError::MalformedHopData);
+        }
+        let data_end = prefix + (length as usize);
+        let shift = data_end + mac_length;
+        let data = buffer[prefix..data_end].to_vec();
+        let item_hmac =
+            GenericArray::<u8, B::MacLength>::clone_from_slice(&buffer[data_end..shift]);
+
+        B::PayloadCipher::decrypt(&local.shared_secret, message.as_mut())?;
+        if constant_time_is_zero(item_hmac.as_ref()) {
+            strip_tag::<B>(message.as_mut())?;
+            Ok(VariableProcessed::Exit {
+                data: data,
+                message: message,
+            })
+        } else {
+            let next = VariableMessage {
+                routing_info: GenericArray::clone_from_slice(
+                    &buffer[shift..(shift + Self::size())],
+                ),
+                hmac: item_hmac,
+                message: message,
+            };
+            Ok(VariableProcessed::Forward {
+                data: data,
+                next: next,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "serde-support")]
+mod serde_m {
+    use super::{VariableMessage, Sphinx};
+
+    use generic_array::{GenericArray, ArrayLength};
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use core::marker::PhantomData;
+    use core::fmt;
+
+    impl<B, R, P> Serialize for VariableMessage<B, R, P>
+    where
+        B: Sphinx,
+        R: ArrayLength<u8>,
+        P: AsMut<[u8]> + Serialize,
+    {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            use serde::ser::SerializeTuple;
+
+            let mut tuple = serializer.serialize_tuple(3)?;
+            tuple.serialize_element(&self.routing_info)?;
+            tuple.serialize_element(&self.hmac)?;
+            tuple.serialize_element(&self.message)?;
+            tuple.end()
+        }
+    }
+
+    impl<'de, B, R, P> Deserialize<'de> for VariableMessage<B, R, P>
+    where
+        B: Sphinx,
+        R: ArrayLength<u8>,
+        P: AsMut<[u8]> + for<'d> Deserialize<'d>,
+    {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            use serde::de::{Visitor, SeqAccess, Error};
+
+            struct V<B, R, P>
+            where
+                B: Sphinx,
+                R: ArrayLength<u8>,
+                P: AsMut<[u8]> + for<'d> Deserialize<'d>,
+            {
+                phantom_data: PhantomData<(B, R, P)>,
+            }
+
+            impl<'de, B, R, P> Visitor<'de> for V<B, R, P>
+            where
+                B: Sphinx,
+                R: ArrayLength<u8>,
+                P: AsMut<[u8]> + for<'d> Deserialize<'d>,
+            {
+                type Value = VariableMessage<B, R, P>;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, "bytes")
+                }
+
+                fn visit_seq<S>(self, mut sequence: S) -> Result<Self::Value, S::Error>
+                where
+                    S: SeqAccess<'de>,
+                {
+                    let r: GenericArray<u8, R> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let m: GenericArray<u8, B::MacLength> = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+                    let ms: P = sequence
+                        .next_element()?
+                        .ok_or(Error::custom("not enough data"))?;
+
+                    Ok(VariableMessage {
+                        routing_info: r,
+                        hmac: m,
+                        message: ms,
+                    })
+                }
+            }
+
+            deserializer.deserialize_tuple(
+                3,
+                V {
+                    phantom_data: PhantomData::<(B, R, P)>,
+                },
+            )
+        }
+    }
+}
+
+mod implementations {
+    use super::{VariableMessage, Sphinx};
+    use generic_array::ArrayLength;
+    use core::fmt;
+
+    impl<B, R, P> fmt::Debug for VariableMessage<B, R, P>
+    where
+        B: Sphinx,
+        R: ArrayLength<u8>,
+        P: fmt::Debug + AsMut<[u8]>,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("VariableMessage")
+                .field("routing_info", &self.routing_info)
+                .field("hmac", &self.hmac)
+                .field("message", &self.message)
+                .finish()
+        }
+    }
+
+    impl<B, R, P> PartialEq for VariableMessage<B, R, P>
+    where
+        B: Sphinx,
+        R: ArrayLength<u8>,
+        P: PartialEq + AsMut<[u8]>,
+    {
+        fn eq(&self, other: &Self) -> bool {
+            self.routing_info.eq(&other.routing_info)
+                && self.hmac.eq(&other.hmac)
+                && self.message.eq(&other.message)
+        }
+    }
+
+    impl<B, R, P> Eq for VariableMessage<B, R, P>
+    where
+        B: Sphinx,
+        R: ArrayLength<u8>,
+        P: PartialEq + AsMut<[u8]>,
+    {
+    }
+}
@@ -0,0 +1,140 @@
+use generic_array::{GenericArray, typenum::U32};
+use rac::{LineValid, Scalar, Curve};
+use curve25519_dalek::{
+    constants::X25519_BASEPOINT, montgomery::MontgomeryPoint, scalar::Scalar as DalekScalar,
+};
+
+// a secret key or a blinding factor, the bytes are clamped as x25519 does, a clamped key `k`
+// is a multiple of 8 below 2^255, it is kept as `k / 8` that is exact and below the group order,
+// the multiplication by 8 is done on the point, so the cofactor is cleared as x25519 does,
+// and the blinding factors multiply modulo the group order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct X25519Scalar(DalekScalar);
+
+impl Drop for X25519Scalar {
+    fn drop(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+impl X25519Scalar {
+    pub fn clamp(bytes: [u8; 32]) -> Self {
+        let mut bytes = bytes;
+        bytes[0] &= 248;
+        bytes[31] &= 127;
+        bytes[31] |= 64;
+        let mut quotient = [0; 32];
+        for i in 0..32 {
+            quotient[i] = (bytes[i] >> 3) | bytes.get(i + 1).map_or(0, |&b| b << 5);
+        }
+        X25519Scalar(DalekScalar::from_bytes_mod_order(quotient))
+    }
+
+    fn cofactor() -> DalekScalar {
+        DalekScalar::from(8u8)
+    }
+}
+
+impl LineValid for X25519Scalar {
+    type Length = U32;
+
+    fn try_clone_array(a: &GenericArray<u8, Self::Length>) -> Result<Self, ()> {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(a.as_ref());
+        Ok(X25519Scalar::clamp(bytes))
+    }
+
+    // `8 * (k / 8)`, a key reads back as itself, a product of keys
+    // in general has no clamped form and reads back as a different value
+    fn clone_line(&self) -> GenericArray<u8, Self::Length> {
+        let quotient = self.0.as_bytes();
+        let mut bytes = GenericArray::default();
+        for i in 0..32 {
+            bytes[i] = (quotient[i] << 3) | if i == 0 { 0 } else { quotient[i - 1] >> 5 };
+        }
+        bytes
+    }
+}
+
+impl Scalar for X25519Scalar {
+    fn add_ff(&self, rhs: &Self) -> Result<Self, ()> {
+        Ok(X25519Scalar(self.0 + rhs.0))
+    }
+
+    // `k * m / 8 = 8 * (k / 8) * (m / 8)`
+    fn mul_ff(&self, rhs: &Self) -> Result<Self, ()> {
+        Ok(X25519Scalar(self.0 * rhs.0 * Self::cofactor()))
+    }
+
+    // `1 / k / 8 = 1 / (64 * (k / 8))`
+    fn inv_ff(&self) -> Result<Self, ()> {
+        if self.0 == DalekScalar::zero() {
+            Err(())
+        } else {
+            Ok(X25519Scalar(
+                (self.0 * Self::cofactor() * Self::cofactor()).invert(),
+            ))
+        }
+    }
+}
+
+// the u coordinate of a point on curve25519, 32 bytes on the wire
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct X25519Point(MontgomeryPoint);
+
+impl X25519Point {
+    pub fn from_secret(secret_key: &X25519Scalar) -> Self {
+        X25519Point::base().exp_ec(secret_key)
+    }
+}
+
+impl LineValid for X25519Point {
+    type Length = U32;
+
+    // any 32 bytes are a u coordinate for x25519, the points of small order are rejected,
+    // the cofactor multiplication takes them to the identity and the shared secret to zero
+    fn try_clone_array(a: &GenericArray<u8, Self::Length>) -> Result<Self, ()> {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(a.as_ref());
+        let point = MontgomeryPoint(bytes);
+        if (point * X25519Scalar::cofactor()).as_bytes() == &[0; 32] {
+            Err(())
+        } else {
+            Ok(X25519Point(point))
+        }
+    }
+
+    fn clone_line(&self) -> GenericArray<u8, Self::Length> {
+        GenericArray::clone_from_slice(self.0.as_bytes())
+    }
+}
+
+impl Curve for X25519Point {
+    type Scalar = X25519Scalar;
+    type CompressedCurve = Self;
+
+    fn base() -> Self {
+        X25519Point(X25519_BASEPOINT)
+    }
+
+    // the u coordinate does not determine the sum of two points, and sphinx never adds points,
+    // so this fails, the result is the all zero encoding of the identity rather than a panic,
+    // `try_clone_array` and the key exchange reject it, it can not pass for a key
+    fn mul_ec(&self, _rhs: &Self) -> Self {
+        X25519Point(MontgomeryPoint([0; 32]))
+    }
+
+    // the same as x25519 for the clamped key `k`, the twist included
+    fn exp_ec(&self, rhs: &Self::Scalar) -> Self {
+        X25519Point((self.0 * X25519Scalar::cofactor()) * rhs.0)
+    }
+
+    fn decompress(packed: &Self::CompressedCurve) -> Result<Self, ()> {
+        Ok(*packed)
+    }
+
+    fn compress(&self) -> Self::CompressedCurve {
+        *self
+    }
+}